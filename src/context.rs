@@ -1,9 +1,15 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::{btree_map, hash_map, HashMap, HashSet},
+    panic::RefUnwindSafe,
+    rc::Rc,
+    sync::Arc,
+};
 
 use crate::{
-    parse::{self, Tokenize},
+    parse::{self, Delimiters, Location, Tokenize},
     value::Value,
-    Object, Variable,
+    Object, Segment, Variable,
 };
 
 /// Context for expanding templates
@@ -14,24 +20,181 @@ use crate::{
 /// ctx.define(Variable::single("a"), "b");
 /// assert_eq!(ctx.render("{{ a }}"), Ok("b".to_owned()));
 /// ```
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Context<'a> {
     vars: HashMap<Cow<'a, str>, Value<'a>>,
+    partials: HashMap<String, String>,
+    depth_limit: usize,
+    escape: EscapeMode,
+    delimiters: Delimiters,
+    partial_loader: Option<PartialLoader>,
+    numeric_coercion: bool,
+    escape_char: Option<char>,
+}
+impl Default for Context<'_> {
+    fn default() -> Self {
+        Self {
+            vars: HashMap::default(),
+            partials: HashMap::default(),
+            depth_limit: Self::DEFAULT_DEPTH_LIMIT,
+            escape: EscapeMode::default(),
+            delimiters: Delimiters::default(),
+            partial_loader: None,
+            numeric_coercion: false,
+            escape_char: Some('\\'),
+        }
+    }
+}
+
+/// Callback used to find partials which were not registered, see [`Context::set_partial_loader`]
+#[derive(Clone)]
+struct PartialLoader(Arc<LoadPartial>);
+type LoadPartial = dyn Fn(&str) -> Option<String> + Send + Sync + RefUnwindSafe;
+impl std::fmt::Debug for PartialLoader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PartialLoader(..)")
+    }
+}
+/// Loaders are only equal if they are the same loader
+impl PartialEq for PartialLoader {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for PartialLoader {}
+
+/// How expanded variables are escaped, see [`Context::set_escape`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum EscapeMode {
+    /// Values are inserted as-is
+    #[default]
+    None,
+    /// `&`, `<`, `>`, `"` and `'` are replaced with HTML entities
+    Html,
+    /// Escaped to be placed inside a JSON string, `"`, `\` and control characters are
+    /// replaced with escape sequences. The surrounding quotes are not added
+    ///
+    /// This is always used for `{{json var}}`
+    Json,
+}
+impl EscapeMode {
+    /// Write `s` escaped according to this mode
+    fn write(self, s: &str, write: &mut impl FnMut(&str) -> Result<()>) -> Result<()> {
+        if self == EscapeMode::None {
+            return write(s);
+        }
+        let mut tail = 0;
+        let mut unicode_escape = *b"\\u0000";
+        for (i, ch) in s.char_indices() {
+            let escaped = match (self, ch) {
+                (EscapeMode::Html, '&') => "&amp;",
+                (EscapeMode::Html, '<') => "&lt;",
+                (EscapeMode::Html, '>') => "&gt;",
+                (EscapeMode::Html, '"') => "&quot;",
+                (EscapeMode::Html, '\'') => "&#x27;",
+                (EscapeMode::Json, '"') => "\\\"",
+                (EscapeMode::Json, '\\') => "\\\\",
+                (EscapeMode::Json, '\n') => "\\n",
+                (EscapeMode::Json, '\r') => "\\r",
+                (EscapeMode::Json, '\t') => "\\t",
+                (EscapeMode::Json, '\u{8}') => "\\b",
+                (EscapeMode::Json, '\u{c}') => "\\f",
+                (EscapeMode::Json, ch) if (ch as u32) < 0x20 => {
+                    const HEX: &[u8; 16] = b"0123456789abcdef";
+                    unicode_escape[4] = HEX[ch as usize >> 4];
+                    unicode_escape[5] = HEX[ch as usize & 0xf];
+                    parse::str_from_utf8(&unicode_escape)
+                }
+                _ => continue,
+            };
+            write(&s[tail..i])?;
+            write(escaped)?;
+            tail = i + 1;
+        }
+        write(&s[tail..])
+    }
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 impl std::error::Error for Error {}
 
 /// Errors that may happen during rendering
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug)]
 #[non_exhaustive]
 pub enum Error {
     /// Forwarded from parsing
     Parse(parse::Error),
     /// Tried to expand a template variable that we don't have a value for
     MissingVariable(Variable<'static>),
+    /// A variable used anywhere in a template had no value
+    ///
+    /// Only returned by [`render_strict`](Context::render_strict)
+    UndefinedVariable {
+        #[allow(missing_docs)]
+        variable: Variable<'static>,
+        /// Start of the tag which uses the variable, in the template or partial it is in
+        offset: Location,
+    },
     /// Tried to expand an object template variable
     TriedToExpandObject(Variable<'static>),
+    /// Tried to expand an array template variable
+    TriedToExpandArray(Variable<'static>),
+    /// Tried to loop over a variable which is not an array or object
+    NotIterable(Variable<'static>),
+    /// Tried to compare a variable in an `{{#if}}` with a value of a different kind, such as a
+    /// number with a string which is not one
+    ///
+    /// Only returned by [`render_strict`](Context::render_strict), otherwise the comparison is
+    /// false
+    NotComparable(Variable<'static>),
+    /// Tried to include a partial which has not been registered
+    MissingPartial(String),
+    /// Partials were nested deeper than the limit, most likely because a partial includes itself
+    ///
+    /// Holds the name of the partial which would have gone over the limit, or of the partial
+    /// with the block which would have. See [`Context::set_depth_limit`]
+    PartialRecursion(String),
+    /// Blocks were nested deeper than the limit outside of any partial, see
+    /// [`Context::set_depth_limit`]
+    NestedTooDeeply {
+        /// Start of the block tag which would have gone over the limit, in the template or
+        /// partial it is in
+        offset: Location,
+    },
+    /// Writing rendered output failed
+    ///
+    /// Only returned by [`render_to_writer`](Context::render_to_writer). Compares equal to
+    /// any other `Io` error of the same [`kind`](std::io::Error::kind)
+    Io(std::io::Error),
+}
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Error::Parse(l), Error::Parse(r)) => l == r,
+            (Error::MissingVariable(l), Error::MissingVariable(r)) => l == r,
+            (
+                Error::UndefinedVariable {
+                    variable: lv,
+                    offset: lo,
+                },
+                Error::UndefinedVariable {
+                    variable: rv,
+                    offset: ro,
+                },
+            ) => lv == rv && lo == ro,
+            (Error::TriedToExpandObject(l), Error::TriedToExpandObject(r)) => l == r,
+            (Error::TriedToExpandArray(l), Error::TriedToExpandArray(r)) => l == r,
+            (Error::NotIterable(l), Error::NotIterable(r)) => l == r,
+            (Error::NotComparable(l), Error::NotComparable(r)) => l == r,
+            (Error::MissingPartial(l), Error::MissingPartial(r)) => l == r,
+            (Error::PartialRecursion(l), Error::PartialRecursion(r)) => l == r,
+            (Error::NestedTooDeeply { offset: l }, Error::NestedTooDeeply { offset: r }) => l == r,
+            (Error::Io(l), Error::Io(r)) => l.kind() == r.kind(),
+            _ => false,
+        }
+    }
 }
+impl Eq for Error {}
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -39,9 +202,33 @@ impl std::fmt::Display for Error {
             Error::MissingVariable(var) => {
                 f.write_fmt(format_args!("missing variable in template: '{var}'"))
             }
+            Error::UndefinedVariable { variable, offset } => f.write_fmt(format_args!(
+                "undefined variable '{variable}' at line {line} column {col}",
+                line = offset.line + 1,
+                col = offset.col + 1
+            )),
             Error::TriedToExpandObject(var) => {
                 f.write_fmt(format_args!("tried to expand object variable: '{var}'"))
             }
+            Error::TriedToExpandArray(var) => {
+                f.write_fmt(format_args!("tried to expand array variable: '{var}'"))
+            }
+            Error::NotIterable(var) => f.write_fmt(format_args!(
+                "tried to loop over non-iterable variable: '{var}'"
+            )),
+            Error::NotComparable(var) => f.write_fmt(format_args!(
+                "tried to compare variable with a value of a different kind: '{var}'"
+            )),
+            Error::MissingPartial(name) => f.write_fmt(format_args!("missing partial: '{name}'")),
+            Error::PartialRecursion(name) => f.write_fmt(format_args!(
+                "partials nested too deeply including: '{name}'"
+            )),
+            Error::NestedTooDeeply { offset } => f.write_fmt(format_args!(
+                "blocks and partials nested too deeply at line {line} column {col}",
+                line = offset.line + 1,
+                col = offset.col + 1
+            )),
+            Error::Io(e) => f.write_fmt(format_args!("failed to write output: {e}")),
         }
     }
 }
@@ -51,20 +238,89 @@ impl From<parse::Error> for Error {
         Self::Parse(value)
     }
 }
-macro_rules! force_object {
-    ($entry:expr) => {
-        $entry
-            .and_modify(|o| match o {
-                Value::String(_) => *o = Object::new().into(),
-                Value::Object(_) => {}
-            })
-            .or_insert(Object::new().into())
-            .as_object_mut()
-            .unwrap()
+/// Scope for one iteration of an `{{#each}}` block
+struct Frame<'v, 'a> {
+    this: &'v Value<'a>,
+    index: usize,
+    key: Option<&'v Cow<'a, str>>,
+    last: bool,
+}
+
+fn value_str<'v>(var: &Variable<'_>, value: &'v Value<'_>) -> Result<&'v str> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Object(_) => Err(Error::TriedToExpandObject(var.clone().into_owned())),
+        Value::Array(_) => Err(Error::TriedToExpandArray(var.clone().into_owned())),
+    }
+}
+
+/// Merge `value` into `into`, objects are merged property by property and anything else
+/// replaces the existing value
+fn merge_value<'a>(into: &mut Value<'a>, value: Value<'a>) {
+    match (into, value) {
+        (Value::Object(into), Value::Object(value)) => {
+            for (key, value) in value.values {
+                match into.values.entry(key) {
+                    btree_map::Entry::Occupied(e) => merge_value(e.into_mut(), value),
+                    btree_map::Entry::Vacant(e) => {
+                        e.insert(value);
+                    }
+                }
+            }
+        }
+        (into, value) => *into = value,
+    }
+}
+
+/// Placeholder for values which have not been defined yet, replaced once they are
+const UNSET: Value<'static> = Value::String(Cow::Borrowed(""));
+
+/// Value at `segment` under `parent` for [`Context::define`], creating it if needed
+///
+/// Indexes up to the length of an array write into it, the length itself appends an element.
+/// Other indexes and keys write into objects, an array is turned into an object with a
+/// property for each element so that arrays are never padded out to a large index. If `parent`
+/// is neither it is replaced with an empty one of the right kind
+fn child_mut<'v, 'a>(parent: &'v mut Value<'a>, segment: Segment<'a>) -> &'v mut Value<'a> {
+    let len = match parent {
+        Value::Array(items) => Some(items.len()),
+        Value::Object(_) => None,
+        _ => Some(0),
     };
+    match segment.as_index() {
+        Some(index) if len.is_some_and(|len| index <= len) => {
+            if !parent.is_array() {
+                *parent = Value::Array(Vec::new());
+            }
+            let Value::Array(items) = parent else {
+                unreachable!()
+            };
+            if index == items.len() {
+                items.push(UNSET);
+            }
+            &mut items[index]
+        }
+        _ => {
+            if let Value::Array(items) = parent {
+                let items = std::mem::take(items).into_iter().enumerate();
+                *parent = Value::Object(Object {
+                    values: items.map(|(i, v)| (Cow::Owned(i.to_string()), v)).collect(),
+                });
+            } else if !parent.is_object() {
+                *parent = Object::new().into();
+            }
+            let Value::Object(obj) = parent else {
+                unreachable!()
+            };
+            obj.values.entry(segment.into_key()).or_insert(UNSET)
+        }
+    }
 }
 
 impl<'a> Context<'a> {
+    /// Default for [`set_depth_limit`](Context::set_depth_limit)
+    pub const DEFAULT_DEPTH_LIMIT: usize = 64;
+
     /// Create a new context with no variables defined
     pub fn new() -> Self {
         Self::default()
@@ -73,6 +329,15 @@ impl<'a> Context<'a> {
     ///
     /// Values are merged into existing definitions where possible
     ///
+    /// All variables live in a single tree, defining a path such as `user.name` creates (or adds
+    /// to) an object `user` with a property `name`. So defining each path separately is the
+    /// same as defining `user` as an object, either way `{{ user.name }}` resolves to the same
+    /// value and the two styles can be mixed freely. When definitions overlap the later one takes
+    /// priority, objects are merged property by property and anything else replaces what was
+    /// there before. An index in a path, such as the `1` in `items.1`, sets that element of an
+    /// array, or appends to it if the index is its length. Indexes further past the end are
+    /// used as property names instead, turning the array into an object
+    ///
     /// ```
     /// # use handybars::{Context, Object, Variable};
     /// # use std::str::FromStr;
@@ -93,31 +358,176 @@ impl<'a> Context<'a> {
     ///
     pub fn define(&mut self, var: Variable<'a>, value: impl Into<Value<'a>>) -> &mut Self {
         match var.inner {
-            crate::VariableInner::Segments(mut segs) => {
-                let mut parent = force_object!(self.vars.entry(segs[0].clone()));
-                let last = segs.pop().unwrap();
-                for level in segs.into_iter().skip(1) {
-                    parent = force_object!(parent.values.entry(level));
+            crate::VariableInner::Segments(segs) => {
+                let mut segs = segs.into_iter();
+                let first = segs.next().expect("variables have at least one segment");
+                let mut target = self.vars.entry(first.into_key()).or_insert(UNSET);
+                for segment in segs {
+                    target = child_mut(target, segment);
                 }
-                parent.add_property(last, value);
-            }
-            crate::VariableInner::Single(s) => {
-                let mut value = Some(value);
-                self.vars
-                    .entry(s.clone())
-                    .and_modify(|o| match o {
-                        Value::String(_) => {
-                            *o = value.take().unwrap().into();
-                        }
-                        Value::Object(o) => {
-                            o.add_property(s, value.take().unwrap());
-                        }
-                    })
-                    .or_insert_with(|| value.take().unwrap().into());
+                merge_value(target, value.into());
             }
+            crate::VariableInner::Single(s) => match self.vars.entry(s.into_key()) {
+                hash_map::Entry::Occupied(e) => merge_value(e.into_mut(), value.into()),
+                hash_map::Entry::Vacant(e) => {
+                    e.insert(value.into());
+                }
+            },
         }
         self
     }
+    /// Set how variables are escaped when rendering
+    ///
+    /// The default is [`EscapeMode::None`]. Variables in triple braces (`{{{ var }}}`) are
+    /// never escaped
+    ///
+    /// ```
+    /// # use handybars::{Context, EscapeMode, Variable};
+    /// let mut ctx = Context::new().with_define(Variable::single("a"), "<b>");
+    /// ctx.set_escape(EscapeMode::Html);
+    /// assert_eq!(ctx.render("{{ a }} {{{ a }}}"), Ok("&lt;b&gt; <b>".to_owned()));
+    /// ```
+    pub fn set_escape(&mut self, mode: EscapeMode) -> &mut Self {
+        self.escape = mode;
+        self
+    }
+    /// Builder version of [`set_escape`](Context::set_escape)
+    pub fn with_escape(mut self, mode: EscapeMode) -> Self {
+        self.set_escape(mode);
+        self
+    }
+    /// Set whether strings which look like numbers are compared as numbers
+    ///
+    /// Values are always strings, including those converted from numbers, so by default
+    /// `{{#if count > 10}}` never holds as a string is not comparable to the number `10`.
+    /// With coercion strings which parse as numbers are treated as numbers when comparing,
+    /// strings which don't are still not comparable to numbers. Comparing two strings orders
+    /// them by their text either way
+    ///
+    /// ```
+    /// # use handybars::{Context, Variable};
+    /// let mut ctx = Context::new().with_define(Variable::single("count"), "42");
+    /// let template = "{{#if count > 10}}many{{else}}few{{/if}}";
+    /// assert_eq!(ctx.render(template), Ok("few".to_owned()));
+    /// ctx.set_numeric_coercion(true);
+    /// assert_eq!(ctx.render(template), Ok("many".to_owned()));
+    /// ```
+    pub fn set_numeric_coercion(&mut self, coerce: bool) -> &mut Self {
+        self.numeric_coercion = coerce;
+        self
+    }
+    /// Builder version of [`set_numeric_coercion`](Context::set_numeric_coercion)
+    pub fn with_numeric_coercion(mut self, coerce: bool) -> Self {
+        self.set_numeric_coercion(coerce);
+        self
+    }
+    /// Set the character which escapes an opening delimiter in templates and partials
+    ///
+    /// The default is `\`, `None` turns escaping off. See [`Tokenize::with_escape_char`]
+    ///
+    /// ```
+    /// # use handybars::{Context, Variable};
+    /// let mut ctx = Context::new().with_define(Variable::single("dir"), "x");
+    /// assert_eq!(ctx.render(r"C:\{{ dir }}"), Ok("C:{{ dir }}".to_owned()));
+    /// ctx.set_escape_char(None);
+    /// assert_eq!(ctx.render(r"C:\{{ dir }}"), Ok(r"C:\x".to_owned()));
+    /// ```
+    pub fn set_escape_char(&mut self, escape: Option<char>) -> &mut Self {
+        self.escape_char = escape;
+        self
+    }
+    /// Builder version of [`set_escape_char`](Context::set_escape_char)
+    pub fn with_escape_char(mut self, escape: Option<char>) -> Self {
+        self.set_escape_char(escape);
+        self
+    }
+    /// Set the delimiters used for tags in templates and partials
+    ///
+    /// ```
+    /// # use handybars::{Context, Variable, parse::Delimiters};
+    /// let mut ctx = Context::new().with_define(Variable::single("x"), "y");
+    /// ctx.set_delimiters(Delimiters::new("<%", "%>"));
+    /// assert_eq!(ctx.render("{{x}} <% x %>"), Ok("{{x}} y".to_owned()));
+    /// ```
+    pub fn set_delimiters(&mut self, delimiters: Delimiters) -> &mut Self {
+        self.delimiters = delimiters;
+        self
+    }
+    /// Builder version of [`set_delimiters`](Context::set_delimiters)
+    pub fn with_delimiters(mut self, delimiters: Delimiters) -> Self {
+        self.set_delimiters(delimiters);
+        self
+    }
+    /// Register a template which can be included by other templates with `{{> name }}`
+    ///
+    /// Partials are rendered with the same variables as the template including them,
+    /// registering a partial with an existing name replaces it
+    ///
+    /// ```
+    /// # use handybars::{Context, Variable};
+    /// let mut ctx = Context::new().with_define(Variable::single("name"), "world");
+    /// ctx.register_partial("greeting", "hello {{ name }}");
+    /// assert_eq!(ctx.render("{{> greeting }}!"), Ok("hello world!".to_owned()));
+    /// ```
+    pub fn register_partial(
+        &mut self,
+        name: impl Into<String>,
+        body: impl Into<String>,
+    ) -> &mut Self {
+        self.partials.insert(name.into(), body.into());
+        self
+    }
+    /// Builder version of [`register_partial`](Context::register_partial)
+    pub fn with_partial(mut self, name: impl Into<String>, body: impl Into<String>) -> Self {
+        self.register_partial(name, body);
+        self
+    }
+    /// Set how deeply blocks and partials may be nested while rendering
+    ///
+    /// Blocks and included partials count against the same limit, so a partial inside an
+    /// `{{#if}}` inside a partial is three deep. Including a partial beyond this depth fails with
+    /// [`Error::PartialRecursion`], as does a block inside a partial, and any other block with
+    /// [`Error::NestedTooDeeply`]. This stops a partial which (indirectly) includes itself from
+    /// recursing forever or running out of stack. Defaults to
+    /// [`DEFAULT_DEPTH_LIMIT`](Context::DEFAULT_DEPTH_LIMIT)
+    pub fn set_depth_limit(&mut self, limit: usize) -> &mut Self {
+        self.depth_limit = limit;
+        self
+    }
+    /// Builder version of [`set_depth_limit`](Context::set_depth_limit)
+    pub fn with_depth_limit(mut self, limit: usize) -> Self {
+        self.set_depth_limit(limit);
+        self
+    }
+    /// Set a callback to load partials which have not been registered
+    ///
+    /// When a template includes a partial which was not registered with
+    /// [`register_partial`](Context::register_partial) `loader` is called with its name, if it
+    /// returns `None` rendering fails with [`Error::MissingPartial`]. Within a single render
+    /// the loader is only called once for each name.
+    ///
+    /// ```
+    /// # use handybars::{Context, Variable};
+    /// let mut ctx = Context::new().with_define(Variable::single("x"), "y");
+    /// ctx.set_partial_loader(|name| (name == "header").then(|| "# {{ x }}".to_owned()));
+    /// // e.g. std::fs::read_to_string(format!("{name}.hbs")).ok()
+    /// assert_eq!(ctx.render("{{> header }}"), Ok("# y".to_owned()));
+    /// ```
+    pub fn set_partial_loader(
+        &mut self,
+        loader: impl Fn(&str) -> Option<String> + Send + Sync + RefUnwindSafe + 'static,
+    ) -> &mut Self {
+        self.partial_loader = Some(PartialLoader(Arc::new(loader)));
+        self
+    }
+    /// Builder version of [`set_partial_loader`](Context::set_partial_loader)
+    pub fn with_partial_loader(
+        mut self,
+        loader: impl Fn(&str) -> Option<String> + Send + Sync + RefUnwindSafe + 'static,
+    ) -> Self {
+        self.set_partial_loader(loader);
+        self
+    }
     /// Builder version of [`define`](Context::define)
     pub fn with_define(mut self, var: Variable<'a>, value: impl Into<Value<'a>>) -> Self {
         self.define(var, value);
@@ -154,14 +564,13 @@ impl<'a> Context<'a> {
     pub fn get_value(&self, var: &Variable<'a>) -> Option<&Value<'a>> {
         match &var.inner {
             crate::VariableInner::Segments(segs) => {
-                let mut parent = self.vars.get(&segs[0])?;
-                let last = segs.last().unwrap();
-                for level in segs.iter().skip(1).take(segs.len() - 2) {
-                    parent = parent.as_object()?.property(level)?;
+                let mut parent = self.vars.get(segs[0].to_key().as_ref())?;
+                for level in segs.iter().skip(1) {
+                    parent = parent.lookup(level)?;
                 }
-                parent.as_object()?.property(last)
+                Some(parent)
             }
-            crate::VariableInner::Single(s) => self.vars.get(s),
+            crate::VariableInner::Single(s) => self.vars.get(s.to_key().as_ref()),
         }
     }
     /// Expand a single variable
@@ -173,14 +582,48 @@ impl<'a> Context<'a> {
     /// assert_eq!(ctx.expand(&var), Ok("b".to_owned()));
     /// ```
     pub fn expand(&self, var: &Variable<'a>) -> Result<String> {
+        self.expand_str(var).map(ToOwned::to_owned)
+    }
+    fn expand_str(&self, var: &Variable<'a>) -> Result<&str> {
         let val = self
             .get_value(var)
             .ok_or_else(|| Error::MissingVariable(var.clone().into_owned()))?;
-        if val.is_object() {
-            Err(Error::TriedToExpandObject(var.clone().into_owned()))
-        } else {
-            Ok(val.as_string().unwrap().clone().into_owned())
-        }
+        value_str(var, val)
+    }
+    /// Resolve a variable which may refer to the current `{{#each}}` iteration
+    ///
+    /// `this` is the element of the innermost loop (outside of loops it is an ordinary
+    /// variable), `@index`, `@key`, `@first` and `@last`
+    /// describe its position. `@first` and `@last` are `"true"` or empty (and so falsy)
+    fn lookup<'v>(
+        &'v self,
+        var: &Variable<'_>,
+        frame: Option<&Frame<'v, 'a>>,
+    ) -> Option<Cow<'v, Value<'a>>> {
+        let mut segments = var.segments();
+        let first = segments.next().unwrap();
+        let root = match first.as_key() {
+            Some("this") if frame.is_some() => frame?.this,
+            Some(special) if special.starts_with('@') => {
+                let frame = frame?;
+                if segments.next().is_some() {
+                    return None;
+                }
+                let flag = |set: bool| Value::from(if set { "true" } else { "" });
+                return match special {
+                    "@index" => Some(frame.index.into()),
+                    "@key" => frame.key.cloned().map(Value::String),
+                    "@first" => Some(flag(frame.index == 0)),
+                    "@last" => Some(flag(frame.last)),
+                    _ => None,
+                }
+                .map(Cow::Owned);
+            }
+            _ => self.vars.get(first.to_key().as_ref())?,
+        };
+        segments
+            .try_fold(root, |parent, seg| parent.lookup(seg))
+            .map(Cow::Borrowed)
     }
 
     /// Render a template
@@ -191,20 +634,142 @@ impl<'a> Context<'a> {
     /// let ctx = Context::new().with_define(var.clone(), "b");
     /// assert_eq!(ctx.render("some text {{ a }}"), Ok("some text b".to_owned()));
     /// ```
-    pub fn render<'b>(&self, input: &'b str) -> Result<String> {
+    pub fn render(&self, input: &str) -> Result<String> {
         let mut output = String::new();
-        for token in Tokenize::<'b>::new(input) {
-            let token = token?;
-            match token {
-                parse::Token::Variable(v) => output.push_str(&self.expand(&v)?),
-                parse::Token::Str(s) => {
-                    output.push_str(s);
-                }
+        self.render_with(input, |s| {
+            output.push_str(s);
+            Ok(())
+        })?;
+        Ok(output)
+    }
+    /// Render a template, failing if any variable in it has no value
+    ///
+    /// [`render`](Context::render) only fails for missing variables which would be expanded
+    /// into the output, undefined block conditions are treated as false and looping over an
+    /// undefined variable renders nothing. Here all of those are an
+    /// [`Error::UndefinedVariable`] instead, which makes typos in templates easier to find
+    ///
+    /// ```
+    /// # use handybars::{Context, Error, Variable, parse::Location};
+    /// let ctx = Context::new().with_define(Variable::single("name"), "world");
+    /// assert_eq!(ctx.render_strict("hello {{ name }}"), Ok("hello world".to_owned()));
+    /// assert_eq!(
+    ///     ctx.render_strict("{{#if nmae}}hello{{/if}}"),
+    ///     Err(Error::UndefinedVariable {
+    ///         variable: Variable::single("nmae"),
+    ///         offset: Location::new(0, 0),
+    ///     })
+    /// );
+    /// ```
+    pub fn render_strict(&self, input: &str) -> Result<String> {
+        let mut output = String::new();
+        Renderer::new(
+            self,
+            |s: &str| {
+                output.push_str(s);
+                Ok(())
+            },
+            true,
+        )
+        .render(input, None)?;
+        Ok(output)
+    }
+    /// Find the variables used by a template which currently have no value
+    ///
+    /// Every branch of `{{#if}}` blocks and the bodies of partials are checked, whether they
+    /// would be rendered or not. Each variable is listed once, in the order it first appears.
+    /// Templates which don't parse, partials which can't be found and nesting past the
+    /// [depth limit](Context::set_depth_limit) are errors, the same as when rendering
+    ///
+    /// Names local to a loop, `this` and `@` variables inside `{{#each}}` blocks, depend on the
+    /// value being looped over and are not checked. So even with an empty list
+    /// [`render_strict`](Context::render_strict) can fail for them, e.g. `{{@key}}` when looping
+    /// over an array or `{{this.name}}` for an element without a `name`
+    ///
+    /// ```
+    /// # use handybars::{Context, Variable};
+    /// let ctx = Context::new().with_define(Variable::single("items"), vec!["a"]);
+    /// assert_eq!(
+    ///     ctx.check("{{#each items}}{{ this }}{{ sep }}{{/each}}"),
+    ///     Ok(vec![Variable::single("sep")])
+    /// );
+    /// ```
+    pub fn check(&self, input: &str) -> Result<Vec<Variable<'static>>> {
+        let mut missing = Vec::new();
+        Renderer::new(self, |_: &str| Ok(()), false).check(
+            input,
+            false,
+            &mut missing,
+            &mut HashSet::new(),
+        )?;
+        Ok(missing)
+    }
+    /// Tokenize a template with the delimiters and escape character in use, recording the
+    /// locations of variables for errors
+    fn tokenize<'i>(&self, input: &'i str) -> Tokenize<'i> {
+        Tokenize::new(input)
+            .with_delimiters(self.delimiters.clone())
+            .with_escape_char(self.escape_char)
+            .with_variable_locations()
+    }
+    /// Render a template directly into a writer
+    ///
+    /// Output is written as each token is expanded, so neither the template output nor
+    /// the values substituted into it are ever buffered as a whole. Failures from `out` are
+    /// reported as [`Error::Io`], rendering stops at the first one. Anything written before
+    /// an error is left in `out`.
+    ///
+    /// ```
+    /// # use handybars::{Context, Variable};
+    /// let ctx = Context::new().with_define(Variable::single("a"), "b");
+    /// let mut out = Vec::new();
+    /// ctx.render_to_writer("some text {{ a }}", &mut out).unwrap();
+    /// assert_eq!(out, b"some text b");
+    /// ```
+    pub fn render_to_writer<W: std::io::Write>(&self, input: &str, out: &mut W) -> Result<()> {
+        self.render_with(input, |s| out.write_all(s.as_bytes()).map_err(Error::Io))
+    }
+    /// Render already tokenized input
+    ///
+    /// Tokens are rendered as they are taken from `tokens`, so they can come from several
+    /// templates or be made up without collecting them first. Like [`render`](Context::render)
+    /// undefined block conditions are false, there is no source to give locations for strict
+    /// rendering. Partials are still tokenized from their source
+    ///
+    /// ```
+    /// # use handybars::{Context, Variable, parse::{Token, Tokenize}};
+    /// let ctx = Context::new().with_define(Variable::single("a"), "b");
+    /// let tokens = Tokenize::new("{{ a }}").map(Result::unwrap).chain([Token::Str("!")]);
+    /// assert_eq!(ctx.render_tokens(tokens), Ok("b!".to_owned()));
+    /// ```
+    pub fn render_tokens<'t>(
+        &self,
+        tokens: impl IntoIterator<Item = parse::Token<'t>>,
+    ) -> Result<String> {
+        let mut output = String::new();
+        let mut renderer = Renderer::new(
+            self,
+            |s: &str| {
+                output.push_str(s);
+                Ok(())
+            },
+            false,
+        );
+        // made up tokens have no source, every variable is given the same location
+        let mut locations = Vec::new();
+        for token in tokens {
+            let count = parse::variable_count(&token);
+            if locations.len() < count {
+                locations.resize(count, Location::zero());
             }
+            renderer.render_token(&token, None, &locations[..count])?;
         }
         Ok(output)
     }
-    /// Append another `Context`'s variables
+    fn render_with(&self, input: &str, write: impl FnMut(&str) -> Result<()>) -> Result<()> {
+        Renderer::new(self, write, false).render(input, None)
+    }
+    /// Append another `Context`'s variables and partials
     ///
     /// This operates in place, see [`merge`](Context::merge) for a streamable version
     ///
@@ -216,6 +781,7 @@ impl<'a> Context<'a> {
     /// ```
     pub fn append(&mut self, other: Self) -> &mut Self {
         self.vars.extend(other.vars);
+        self.partials.extend(other.partials);
         self
     }
     /// Stream version of `append`
@@ -225,6 +791,368 @@ impl<'a> Context<'a> {
     }
 }
 
+/// One side of a comparison in an `{{#if}}`
+enum Comparand<'v> {
+    Number(f64),
+    Str(&'v str),
+}
+
+/// State for a single call to render
+struct Renderer<'c, 'a, F> {
+    ctx: &'c Context<'a>,
+    write: F,
+    /// Number of blocks and partials currently being rendered
+    depth: usize,
+    /// Name of the innermost partial being rendered
+    partial: Option<String>,
+    strict: bool,
+    /// Partials returned by the loader so far, including `None` for ones it didn't find
+    loaded: HashMap<String, Option<Rc<str>>>,
+}
+
+impl<'c, 'a, F: FnMut(&str) -> Result<()>> Renderer<'c, 'a, F> {
+    fn new(ctx: &'c Context<'a>, write: F, strict: bool) -> Self {
+        Self {
+            ctx,
+            write,
+            depth: 0,
+            partial: None,
+            strict,
+            loaded: HashMap::new(),
+        }
+    }
+    /// Body of the partial `name` from the loader, only calling it the first time
+    fn load_partial(&mut self, name: &str) -> Option<Rc<str>> {
+        let loader = &self.ctx.partial_loader.as_ref()?.0;
+        self.loaded
+            .entry(name.to_owned())
+            .or_insert_with(|| loader(name).map(Rc::from))
+            .clone()
+    }
+    /// Call `f` one level deeper, or fail with `error` if that is over the depth limit
+    fn nested<R>(
+        &mut self,
+        error: impl FnOnce(&Self) -> Error,
+        f: impl FnOnce(&mut Self) -> Result<R>,
+    ) -> Result<R> {
+        if self.depth >= self.ctx.depth_limit {
+            return Err(error(self));
+        }
+        self.depth += 1;
+        let out = f(self);
+        self.depth -= 1;
+        out
+    }
+    /// Error for the block at `location` going over the depth limit
+    ///
+    /// Inside a partial the partial is blamed, since it most likely (indirectly) includes itself
+    fn block_too_deep(&self, location: Location) -> Error {
+        match &self.partial {
+            Some(name) => Error::PartialRecursion(name.clone()),
+            None => Error::NestedTooDeeply { offset: location },
+        }
+    }
+    fn render(&mut self, input: &str, frame: Option<&Frame<'_, 'a>>) -> Result<()> {
+        let mut tokens = self.ctx.tokenize(input);
+        while let Some(token) = tokens.next() {
+            let token = token?;
+            self.render_token(&token, frame, tokens.variable_locations())?;
+            tokens.forget_variable_locations(parse::variable_count(&token));
+        }
+        Ok(())
+    }
+    /// Look up a variable, in strict mode a missing variable is an error
+    fn lookup<'v>(
+        &self,
+        var: &Variable<'_>,
+        frame: Option<&Frame<'v, 'a>>,
+        location: Location,
+    ) -> Result<Option<Cow<'v, Value<'a>>>>
+    where
+        'c: 'v,
+    {
+        match self.ctx.lookup(var, frame) {
+            None if self.strict => Err(Error::UndefinedVariable {
+                variable: var.clone().into_owned(),
+                offset: location,
+            }),
+            value => Ok(value),
+        }
+    }
+    /// Whether `comparison` holds for `value`, the value of `var`
+    ///
+    /// Values which can't be compared make it false, or are an error in strict mode
+    fn compare(
+        &self,
+        var: &Variable<'_>,
+        value: &Value<'a>,
+        comparison: &parse::Comparison<'_>,
+        frame: Option<&Frame<'_, 'a>>,
+        location: Location,
+    ) -> Result<bool> {
+        let rhs_value;
+        let rhs = match &comparison.rhs {
+            parse::Operand::Number(n) => parse::parse_number(n).map(Comparand::Number),
+            parse::Operand::Variable(rhs) => {
+                let Some(value) = self.lookup(rhs, frame, location)? else {
+                    return Ok(false);
+                };
+                rhs_value = value;
+                self.comparand(&rhs_value)
+            }
+        };
+        let ordering = match (self.comparand(value), rhs) {
+            (Some(Comparand::Number(l)), Some(Comparand::Number(r))) => l.partial_cmp(&r),
+            (Some(Comparand::Str(l)), Some(Comparand::Str(r))) => Some(l.cmp(r)),
+            _ => None,
+        };
+        match ordering {
+            Some(ordering) => Ok(comparison.op.holds(ordering)),
+            None if self.strict => Err(Error::NotComparable(var.clone().into_owned())),
+            None => Ok(false),
+        }
+    }
+    /// How `value` is compared, `None` if it can't be
+    fn comparand<'v>(&self, value: &'v Value<'_>) -> Option<Comparand<'v>> {
+        let s = value.as_string()?;
+        Some(
+            match parse::parse_number(s).filter(|_| self.ctx.numeric_coercion) {
+                Some(n) => Comparand::Number(n),
+                None => Comparand::Str(s),
+            },
+        )
+    }
+    /// Render tokens in order, `locations` starts with the location of their first variable
+    fn render_tokens(
+        &mut self,
+        tokens: &[parse::Token<'_>],
+        frame: Option<&Frame<'_, 'a>>,
+        mut locations: &[Location],
+    ) -> Result<()> {
+        for token in tokens {
+            self.render_token(token, frame, locations)?;
+            locations = &locations[parse::variable_count(token)..];
+        }
+        Ok(())
+    }
+    fn render_token(
+        &mut self,
+        token: &parse::Token<'_>,
+        frame: Option<&Frame<'_, 'a>>,
+        locations: &[Location],
+    ) -> Result<()> {
+        let ctx = self.ctx;
+        match token {
+            parse::Token::Variable(v)
+            | parse::Token::RawVariable(v)
+            | parse::Token::JsonVariable(v) => {
+                let value = self
+                    .lookup(v, frame, locations[0])?
+                    .ok_or_else(|| Error::MissingVariable(v.clone().into_owned()))?;
+                let escape = match token {
+                    parse::Token::RawVariable(_) => EscapeMode::None,
+                    parse::Token::JsonVariable(_) => EscapeMode::Json,
+                    _ => ctx.escape,
+                };
+                escape.write(value_str(v, &value)?, &mut self.write)?
+            }
+            parse::Token::Str(s) => (self.write)(s)?,
+            parse::Token::Block {
+                condition,
+                comparison,
+                then,
+                otherwise,
+            } => {
+                let value = self.lookup(condition, frame, locations[0])?;
+                let truthy = match (value, comparison) {
+                    (None, _) => false,
+                    (Some(value), None) => value.is_truthy(),
+                    (Some(value), Some(comparison)) => {
+                        self.compare(condition, &value, comparison, frame, locations[0])?
+                    }
+                };
+                let (then_locations, otherwise_locations) =
+                    locations[1..].split_at(then.iter().map(parse::variable_count).sum::<usize>());
+                let (body, body_locations) = if truthy {
+                    (then, then_locations)
+                } else {
+                    (otherwise, otherwise_locations)
+                };
+                self.nested(
+                    |this| this.block_too_deep(locations[0]),
+                    |this| this.render_tokens(body, frame, body_locations),
+                )?;
+            }
+            parse::Token::Each { var, body } => {
+                let Some(value) = self.lookup(var, frame, locations[0])? else {
+                    return Ok(());
+                };
+                let mut render_body = |frame: Frame<'_, 'a>| {
+                    self.nested(
+                        |this| this.block_too_deep(locations[0]),
+                        |this| this.render_tokens(body, Some(&frame), &locations[1..]),
+                    )
+                };
+                match &*value {
+                    Value::Array(items) => {
+                        for (index, this) in items.iter().enumerate() {
+                            render_body(Frame {
+                                this,
+                                index,
+                                key: None,
+                                last: index + 1 == items.len(),
+                            })?;
+                        }
+                    }
+                    Value::Object(obj) => {
+                        for (index, (key, this)) in obj.values.iter().enumerate() {
+                            render_body(Frame {
+                                this,
+                                index,
+                                key: Some(key),
+                                last: index + 1 == obj.values.len(),
+                            })?;
+                        }
+                    }
+                    Value::String(_) => return Err(Error::NotIterable(var.clone().into_owned())),
+                }
+            }
+            parse::Token::Partial(name) => {
+                self.with_partial(name, |this, body| this.render(body, frame))?
+            }
+            parse::Token::Comment(_) => {}
+        }
+        Ok(())
+    }
+    /// Call `f` one level deeper with the body of the partial `name`, registered partials are
+    /// preferred over the loader
+    fn with_partial<R>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut Self, &str) -> Result<R>,
+    ) -> Result<R> {
+        let loaded;
+        let body: &str = match self.ctx.partials.get(name) {
+            Some(body) => body,
+            None => {
+                loaded = self
+                    .load_partial(name)
+                    .ok_or_else(|| Error::MissingPartial(name.to_owned()))?;
+                &loaded
+            }
+        };
+        self.nested(
+            |_| Error::PartialRecursion(name.to_owned()),
+            |this| {
+                let outer = this.partial.replace(name.to_owned());
+                let out = f(this, body);
+                this.partial = outer;
+                out
+            },
+        )
+    }
+    /// Collect the variables used by the template `input` which have no value into `missing`
+    ///
+    /// `checked` holds the partials already visited, together with whether they were
+    /// included inside a loop, so that recursive partials are only walked once
+    fn check(
+        &mut self,
+        input: &str,
+        in_loop: bool,
+        missing: &mut Vec<Variable<'static>>,
+        checked: &mut HashSet<(String, bool)>,
+    ) -> Result<()> {
+        let mut tokenize = self.ctx.tokenize(input);
+        let tokens = tokenize
+            .by_ref()
+            .collect::<Result<Vec<_>, parse::Error>>()?;
+        self.check_tokens(
+            &tokens,
+            tokenize.variable_locations(),
+            in_loop,
+            missing,
+            checked,
+        )
+    }
+    /// [`check`](Renderer::check) for tokens, `locations` starts with the location of their
+    /// first variable
+    fn check_tokens(
+        &mut self,
+        tokens: &[parse::Token<'_>],
+        mut locations: &[Location],
+        in_loop: bool,
+        missing: &mut Vec<Variable<'static>>,
+        checked: &mut HashSet<(String, bool)>,
+    ) -> Result<()> {
+        let ctx = self.ctx;
+        let check = |var: &Variable<'_>, missing: &mut Vec<Variable<'static>>| {
+            let local = in_loop
+                && var
+                    .segments()
+                    .next()
+                    .and_then(Segment::as_key)
+                    .is_some_and(|key| key == "this" || key.starts_with('@'));
+            if !local && ctx.lookup(var, None).is_none() && !missing.iter().any(|m| m == var) {
+                missing.push(var.clone().into_owned());
+            }
+        };
+        for token in tokens {
+            match token {
+                parse::Token::Variable(v)
+                | parse::Token::RawVariable(v)
+                | parse::Token::JsonVariable(v) => check(v, missing),
+                parse::Token::Block {
+                    condition,
+                    comparison,
+                    then,
+                    otherwise,
+                } => {
+                    check(condition, missing);
+                    if let Some(parse::Comparison {
+                        rhs: parse::Operand::Variable(rhs),
+                        ..
+                    }) = comparison
+                    {
+                        check(rhs, missing);
+                    }
+                    let (then_locations, otherwise_locations) =
+                        locations[1..].split_at(then.iter().map(parse::variable_count).sum());
+                    self.nested(
+                        |this| this.block_too_deep(locations[0]),
+                        |this| {
+                            this.check_tokens(then, then_locations, in_loop, missing, checked)?;
+                            this.check_tokens(
+                                otherwise,
+                                otherwise_locations,
+                                in_loop,
+                                missing,
+                                checked,
+                            )
+                        },
+                    )?;
+                }
+                parse::Token::Each { var, body } => {
+                    check(var, missing);
+                    self.nested(
+                        |this| this.block_too_deep(locations[0]),
+                        |this| this.check_tokens(body, &locations[1..], true, missing, checked),
+                    )?;
+                }
+                parse::Token::Partial(name) => {
+                    if checked.insert(((*name).to_owned(), in_loop)) {
+                        self.with_partial(name, |this, body| {
+                            this.check(body, in_loop, missing, checked)
+                        })?;
+                    }
+                }
+                parse::Token::Str(_) | parse::Token::Comment(_) => {}
+            }
+            locations = &locations[parse::variable_count(token)..];
+        }
+        Ok(())
+    }
+}
+
 impl<'a> Extend<(Variable<'a>, Value<'a>)> for Context<'a> {
     /// Extend a `Context` with an iterator of defines
     ///
@@ -263,6 +1191,68 @@ mod tests {
         assert_eq!(ctx.render("{{hello.world.test}}"), Ok("val".to_owned()));
     }
 
+    #[test]
+    fn defining_an_index_sets_the_array_element() {
+        let mut ctx = Context::new().with_define(Variable::single("items"), vec!["a", "b", "c"]);
+        ctx.define("items.1".parse().unwrap(), "z");
+        assert_eq!(
+            ctx.render("{{#each items}}{{this}},{{/each}}"),
+            Ok("a,z,c,".to_owned())
+        );
+        ctx.define("items.3.name".parse().unwrap(), "d");
+        assert_eq!(
+            ctx.get_value(&Variable::single("items")),
+            Some(&Value::from(vec![
+                Value::from("a"),
+                Value::from("z"),
+                Value::from("c"),
+                Value::from(Object::new().with_property("name", "d")),
+            ]))
+        );
+    }
+
+    #[test]
+    fn defining_an_index_past_the_end_uses_it_as_a_key() {
+        let mut ctx = Context::new().with_define(Variable::single("items"), vec!["a", "b"]);
+        ctx.define("items.18446744073709551615".parse().unwrap(), "x");
+        ctx.define("big.99999999999999".parse().unwrap(), "y");
+        assert_eq!(
+            ctx.render("{{items.1}} {{items.18446744073709551615}} {{big.99999999999999}}"),
+            Ok("b x y".to_owned())
+        );
+        assert_eq!(
+            ctx.get_value(&Variable::single("big")),
+            Some(&Object::new().with_property("99999999999999", "y").into())
+        );
+    }
+
+    #[test]
+    fn flattened_values_define_the_same_arrays() {
+        let items: Vec<_> = (0..12).collect();
+        let value = Value::from(Object::new().with_property("items", items));
+        let mut ctx = Context::new();
+        for (var, leaf) in value.flatten() {
+            ctx.define(var, leaf.clone());
+        }
+        assert_eq!(
+            ctx.render("{{#each items}}{{this}},{{/each}}"),
+            Ok("0,1,2,3,4,5,6,7,8,9,10,11,".to_owned())
+        );
+        assert_eq!(
+            ctx.get_value(&Variable::single("items")),
+            value.as_object().unwrap().property("items")
+        );
+    }
+
+    #[test]
+    fn index_segments_resolve_object_properties_by_number() {
+        let ctx = Context::new().with_define(
+            Variable::single("a"),
+            Object::new().with_property("0", "zero"),
+        );
+        assert_eq!(ctx.render("{{a.0}}"), Ok("zero".to_owned()));
+    }
+
     #[test]
     fn context_can_register_single_variables() {
         let mut ctx = Context::new();
@@ -303,6 +1293,629 @@ mod tests {
         assert_eq!(ctx.render("{{b}}"), Ok("c".to_owned()));
     }
     #[test]
+    fn if_blocks_render_branch_based_on_truthiness() {
+        let ctx = Context::new()
+            .with_define(Variable::single("yes"), "y")
+            .with_define(Variable::single("empty"), "")
+            .with_define(Variable::single("obj"), Object::new());
+        let template = "{{#if yes}}a{{else}}b{{/if}}{{#if empty}}c{{else}}d{{/if}}\
+                        {{#if missing}}e{{else}}f{{/if}}{{#if obj}}g{{/if}}{{#if empty}}h{{/if}}";
+        assert_eq!(ctx.render(template), Ok("adfg".to_owned()));
+    }
+    #[test]
+    fn nested_if_blocks_render() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "x")
+            .with_define(Variable::single("v"), "value");
+        assert_eq!(
+            ctx.render("{{#if a}}[{{#if b}}b{{else}}{{v}}{{/if}}]{{/if}}"),
+            Ok("[value]".to_owned())
+        );
+    }
+    #[test]
+    fn each_loops_over_arrays() {
+        let ctx = Context::new().with_define(
+            Variable::single("items"),
+            Value::Array(vec!["a".into(), "b".into(), "c".into()]),
+        );
+        assert_eq!(
+            ctx.render("{{#each items}}{{@index}}={{this}}{{#if @last}}.{{else}},{{/if}}{{/each}}"),
+            Ok("0=a,1=b,2=c.".to_owned())
+        );
+    }
+    #[test]
+    fn numeric_segments_index_into_arrays() {
+        let ctx = Context::new().with_define(
+            Variable::single("rows"),
+            vec![
+                Value::from(Object::new().with_property("name", "first")),
+                Value::from(vec!["x", "y", "z"]),
+            ],
+        );
+        assert_eq!(
+            ctx.render("{{ rows.0.name }} {{ rows.1.2 }}"),
+            Ok("first z".to_owned())
+        );
+        assert_eq!(
+            ctx.get_value(&"rows.1.0".parse().unwrap()),
+            Some(&Value::from("x"))
+        );
+        assert_eq!(
+            ctx.get_value(&Variable::from_parts(["rows", "1", "0"])),
+            Some(&Value::from("x"))
+        );
+        assert_eq!(
+            ctx.render("{{#each rows.1}}{{this}}{{/each}}"),
+            Ok("xyz".to_owned())
+        );
+    }
+    #[test]
+    fn out_of_range_indexes_are_missing() {
+        let ctx = Context::new().with_define(Variable::single("items"), vec!["a"]);
+        assert_eq!(
+            ctx.render("{{ items.1 }}"),
+            Err(Error::MissingVariable("items.1".parse().unwrap()))
+        );
+        assert_eq!(
+            ctx.render("{{#if items.5}}yes{{else}}no{{/if}}"),
+            Ok("no".to_owned())
+        );
+        assert_eq!(ctx.get_value(&"items.x".parse().unwrap()), None);
+    }
+    #[test]
+    fn render_strict_reports_undefined_variables_with_location() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "x")
+            .with_define(Variable::single("items"), vec!["1", "2"]);
+        let undefined = |var: &str, col, line| {
+            Err(Error::UndefinedVariable {
+                variable: var.parse().unwrap(),
+                offset: Location::new(col, line),
+            })
+        };
+        assert_eq!(ctx.render_strict("{{a}} {{{a}}}"), Ok("x x".to_owned()));
+        assert_eq!(
+            ctx.render_strict("{{a}}\n  {{b.c}}"),
+            undefined("b.c", 2, 1)
+        );
+        assert_eq!(
+            ctx.render_strict("{{#if nope}}{{/if}}"),
+            undefined("nope", 0, 0)
+        );
+        assert_eq!(
+            ctx.render_strict("{{#each nope}}{{/each}}"),
+            undefined("nope", 0, 0)
+        );
+        assert_eq!(
+            ctx.render_strict("{{#if a}}{{b}}{{else}}{{c}}{{/if}}{{#if x}}{{/if}}"),
+            undefined("b", 9, 0)
+        );
+        assert_eq!(
+            ctx.render_strict("{{#if y}}{{b}}{{else}}{{a}}{{c}}{{/if}}"),
+            undefined("y", 0, 0)
+        );
+        assert_eq!(
+            ctx.render_strict("{{#each items}}{{this}}{{@key}}{{/each}}"),
+            undefined("@key", 23, 0)
+        );
+    }
+    #[test]
+    fn render_strict_tracks_locations_past_skipped_branches() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "")
+            .with_define(Variable::single("e"), Value::Array(vec![]));
+        assert_eq!(
+            ctx.render_strict("{{#if a}}{{x}}{{else}}{{a}}{{/if}}{{#each e}}{{y}}{{/each}}{{z}}"),
+            Err(Error::UndefinedVariable {
+                variable: Variable::single("z"),
+                offset: Location::new(59, 0),
+            })
+        );
+    }
+    #[test]
+    fn comparisons_coerce_numeric_strings_only_when_enabled() {
+        let template = "{{#if count > 10}}many{{else}}few{{/if}}";
+        let mut ctx = Context::new().with_define(Variable::single("count"), "42");
+        assert_eq!(ctx.render(template), Ok("few".to_owned()));
+        assert_eq!(
+            ctx.render_strict(template),
+            Err(Error::NotComparable(Variable::single("count")))
+        );
+        ctx.set_numeric_coercion(true);
+        assert_eq!(ctx.render(template), Ok("many".to_owned()));
+        assert_eq!(ctx.render_strict(template), Ok("many".to_owned()));
+        ctx.define(Variable::single("count"), "9");
+        assert_eq!(ctx.render(template), Ok("few".to_owned()));
+        ctx.define(Variable::single("count"), "lots");
+        assert_eq!(ctx.render(template), Ok("few".to_owned()));
+        assert_eq!(
+            ctx.render_strict(template),
+            Err(Error::NotComparable(Variable::single("count")))
+        );
+    }
+    #[test]
+    fn comparisons_between_variables() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "9")
+            .with_define(Variable::single("b"), "10");
+        let template = "{{#if a < b}}lt{{/if}}{{#if a == a}}eq{{/if}}{{#if a != c}}ne{{/if}}";
+        assert_eq!(ctx.render(template), Ok("eq".to_owned()));
+        assert_eq!(
+            ctx.clone().with_numeric_coercion(true).render(template),
+            Ok("lteq".to_owned())
+        );
+        assert_eq!(ctx.check(template), Ok(vec![Variable::single("c")]));
+    }
+    #[test]
+    fn render_keeps_lenient_blocks() {
+        let ctx = Context::new();
+        assert_eq!(
+            ctx.render("{{#if nope}}x{{/if}}{{#each nope}}y{{/each}}"),
+            Ok(String::new())
+        );
+        assert_eq!(
+            ctx.render("{{nope}}"),
+            Err(Error::MissingVariable(Variable::single("nope")))
+        );
+    }
+    #[test]
+    fn check_reports_missing_but_not_loop_local_variables() {
+        let ctx = Context::new()
+            .with_define(Variable::single("items"), vec!["a", "b"])
+            .with_define(Variable::single("title"), "t");
+        assert_eq!(
+            ctx.check(
+                "{{title}}{{#each items}}{{@index}}{{this}}{{this.name}}{{footer}}{{/each}}\
+                 {{this}}{{@index}}{{#if flag}}{{title}}{{else}}{{footer}}{{/if}}"
+            ),
+            Ok(vec![
+                Variable::single("footer"),
+                Variable::single("this"),
+                Variable::single("@index"),
+                Variable::single("flag"),
+            ])
+        );
+        assert_eq!(
+            ctx.check("{{title}}{{#each items}}{{this}}{{/each}}"),
+            Ok(vec![])
+        );
+        // loop-local names aren't checked, even where strict rendering would fail
+        let template = "{{#each items}}{{@key}}{{/each}}";
+        assert_eq!(ctx.check(template), Ok(vec![]));
+        assert!(matches!(
+            ctx.render_strict(template),
+            Err(Error::UndefinedVariable { .. })
+        ));
+    }
+    #[test]
+    fn check_stops_at_the_depth_limit() {
+        let ctx = Context::new().with_partial_loader(|n| Some(format!("{{{{> {n}x}}}}")));
+        assert_eq!(
+            ctx.check("{{> a}}"),
+            Err(Error::PartialRecursion(format!("a{}", "x".repeat(64))))
+        );
+        let mut ctx = (0..100).fold(Context::new(), |ctx, i| {
+            ctx.with_partial(format!("p{i}"), format!("{{{{> p{}}}}}", i + 1))
+        });
+        ctx.set_depth_limit(3);
+        assert_eq!(
+            ctx.check("{{> p0}}"),
+            Err(Error::PartialRecursion("p3".to_owned()))
+        );
+        assert_eq!(
+            ctx.check("{{#if a}}{{#if b}}{{#each c}}{{#if d}}{{/if}}{{/each}}{{/if}}{{/if}}"),
+            Err(Error::NestedTooDeeply {
+                offset: Location::new(29, 0)
+            })
+        );
+    }
+    #[test]
+    fn check_walks_partials_once() {
+        let ctx = Context::new()
+            .with_partial("a", "{{x}}{{> b}}")
+            .with_partial("b", "{{#each list}}{{this}}{{> a}}{{/each}}");
+        assert_eq!(
+            ctx.check("{{> a}}"),
+            Ok(vec![Variable::single("x"), Variable::single("list")])
+        );
+        assert_eq!(
+            ctx.check("{{> nope}}"),
+            Err(Error::MissingPartial("nope".to_owned()))
+        );
+    }
+    #[test]
+    fn each_loops_over_objects_with_key() {
+        let ctx = Context::new().with_define(
+            Variable::single("obj"),
+            Object::new()
+                .with_property("x", "1")
+                .with_property("y", "2"),
+        );
+        assert_eq!(
+            ctx.render("{{#each obj}}{{#if @first}}[{{/if}}{{@key}}:{{this}};{{/each}}"),
+            Ok("[x:1;y:2;".to_owned())
+        );
+    }
+    #[test]
+    fn each_over_empty_or_missing_renders_nothing() {
+        let ctx = Context::new().with_define(Variable::single("items"), Value::Array(vec![]));
+        assert_eq!(
+            ctx.render("a{{#each items}}{{this}}{{/each}}{{#each missing}}x{{/each}}b"),
+            Ok("ab".to_owned())
+        );
+    }
+    #[test]
+    fn nested_each_shadows_this() {
+        let row = |a: &'static str, b: &'static str| {
+            Object::new()
+                .with_property("name", a)
+                .with_property("cells", Value::Array(vec![b.into(), "z".into()]))
+        };
+        let ctx = Context::new().with_define(
+            Variable::single("rows"),
+            Value::Array(vec![row("r0", "a").into(), row("r1", "b").into()]),
+        );
+        assert_eq!(
+            ctx.render(
+                "{{#each rows}}{{this.name}}({{#each this.cells}}{{@index}}{{this}}{{/each}}){{@index}} {{/each}}"
+            ),
+            Ok("r0(0a1z)0 r1(0b1z)1 ".to_owned())
+        );
+    }
+    #[test]
+    fn each_over_string_is_an_error() {
+        let ctx = Context::new().with_define(Variable::single("s"), "abc");
+        assert_eq!(
+            ctx.render("{{#each s}}{{this}}{{/each}}"),
+            Err(Error::NotIterable(Variable::single("s")))
+        );
+    }
+    #[test]
+    fn loop_variables_are_missing_outside_of_loops() {
+        let ctx = Context::new();
+        assert_eq!(
+            ctx.render("{{@index}}"),
+            Err(Error::MissingVariable(Variable::single("@index")))
+        );
+        assert_eq!(
+            ctx.render("{{this}}"),
+            Err(Error::MissingVariable(Variable::single("this")))
+        );
+    }
+    #[test]
+    fn this_outside_of_loops_is_a_root_variable() {
+        let ctx = Context::new()
+            .with_define(Variable::single("this"), "root")
+            .with_define(Variable::single("items"), vec!["a"]);
+        assert_eq!(
+            ctx.render("{{this}} {{#each items}}{{this}}{{/each}}"),
+            Ok("root a".to_owned())
+        );
+    }
+    #[test]
+    fn html_escape_mode_escapes_variables() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "<script>alert('x & \"y\"')</script>")
+            .with_escape(EscapeMode::Html);
+        assert_eq!(
+            ctx.render("<p>{{a}}</p>"),
+            Ok(
+                "<p>&lt;script&gt;alert(&#x27;x &amp; &quot;y&quot;&#x27;)&lt;/script&gt;</p>"
+                    .to_owned()
+            )
+        );
+    }
+    #[test]
+    fn raw_variables_are_never_escaped() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "<script>")
+            .with_escape(EscapeMode::Html);
+        assert_eq!(ctx.render("{{{a}}}"), Ok("<script>".to_owned()));
+    }
+    #[test]
+    fn variables_are_not_escaped_by_default() {
+        let ctx = Context::new().with_define(Variable::single("a"), "<script>");
+        assert_eq!(
+            ctx.render("{{a}}{{{a}}}"),
+            Ok("<script><script>".to_owned())
+        );
+    }
+    #[test]
+    fn json_escape_mode_escapes_for_json_strings() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "say \"hi\"\n\\ \t\u{1}<>é")
+            .with_escape(EscapeMode::Json);
+        assert_eq!(
+            ctx.render("{\"a\": \"{{a}}\"}"),
+            Ok(r#"{"a": "say \"hi\"\n\\ \t\u0001<>é"}"#.to_owned())
+        );
+    }
+    #[test]
+    fn json_tags_escape_regardless_of_mode() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "<\"x\">\n")
+            .with_escape(EscapeMode::Html);
+        assert_eq!(
+            ctx.render("<p data-x='{{ a }}'>{\"a\": \"{{json a}}\"}</p>"),
+            Ok(r#"<p data-x='&lt;&quot;x&quot;&gt;
+'>{"a": "<\"x\">\n"}</p>"#
+                .to_owned())
+        );
+    }
+    #[test]
+    fn known_special_variables_render_in_loops() {
+        let ctx = Context::new().with_define(Variable::single("items"), vec!["a"]);
+        assert_eq!(
+            ctx.render("{{#each items}}{{@index}}{{#if @first}}f{{/if}}{{/each}}"),
+            Ok("0f".to_owned())
+        );
+        assert_eq!(
+            ctx.render("{{#each items}}{{@indx}}{{/each}}"),
+            Err(Error::Parse(parse::Error::new(
+                (17, 0),
+                17,
+                parse::ErrorKind::ReservedName
+            )))
+        );
+    }
+    #[test]
+    fn partials_render_in_the_current_scope() {
+        let ctx = Context::new()
+            .with_define(
+                Variable::single("items"),
+                Value::Array(vec!["a".into(), "b".into()]),
+            )
+            .with_define(Variable::single("sep"), ",")
+            .with_partial("item", "{{@index}}={{this}}{{> sep}}")
+            .with_partial("sep", "{{sep}}");
+        assert_eq!(
+            ctx.render("{{#each items}}{{> item }}{{/each}}"),
+            Ok("0=a,1=b,".to_owned())
+        );
+    }
+    #[test]
+    fn partials_use_custom_delimiters() {
+        let ctx = Context::new()
+            .with_define(Variable::single("x"), "1")
+            .with_delimiters(Delimiters::new("((", "))"))
+            .with_partial("p", "{{x}}=((x))");
+        assert_eq!(
+            ctx.render("((> p)) ((#if x))ok((/if))"),
+            Ok("{{x}}=1 ok".to_owned())
+        );
+    }
+    #[test]
+    fn partial_loader_is_used_for_unregistered_partials() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let files = HashMap::from([
+            ("item", "<{{this}}>"),
+            ("list", "{{#each items}}{{> item}}{{/each}}"),
+        ]);
+        let ctx = Context::new()
+            .with_define(Variable::single("items"), vec!["a", "b"])
+            .with_partial("item", "[{{this}}]")
+            .with_partial_loader({
+                let calls = calls.clone();
+                move |name| {
+                    calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    files.get(name).map(|s| s.to_string())
+                }
+            });
+        assert_eq!(
+            ctx.render("{{> list}}{{> list}}"),
+            Ok("[a][b][a][b]".to_owned())
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(
+            ctx.render("{{> nope}}{{> nope}}"),
+            Err(Error::MissingPartial("nope".to_owned()))
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+    #[test]
+    fn loaded_partials_are_depth_limited() {
+        let ctx = Context::new()
+            .with_depth_limit(3)
+            .with_partial_loader(|name| Some(format!("{name}{{{{> {name}}}}}")));
+        assert_eq!(
+            ctx.render("{{> a}}"),
+            Err(Error::PartialRecursion("a".to_owned()))
+        );
+    }
+    #[test]
+    fn contexts_with_the_same_loader_are_equal() {
+        let ctx = Context::new().with_partial_loader(|_| None);
+        assert_eq!(ctx.clone(), ctx);
+        assert_ne!(ctx, Context::new().with_partial_loader(|_| None));
+    }
+    #[test]
+    fn contexts_with_a_loader_can_render_in_catch_unwind() {
+        let ctx = Context::new().with_partial_loader(|_| Some("p".to_owned()));
+        assert_eq!(
+            std::panic::catch_unwind(|| ctx.render("{{> a}}")).ok(),
+            Some(Ok("p".to_owned()))
+        );
+    }
+    #[test]
+    fn missing_partial_is_an_error() {
+        assert_eq!(
+            Context::new().render("{{> nope}}"),
+            Err(Error::MissingPartial("nope".to_owned()))
+        );
+    }
+    #[test]
+    fn recursive_partials_hit_the_depth_limit() {
+        let ctx = Context::new()
+            .with_partial("a", "a{{> b}}")
+            .with_partial("b", "b{{> a}}");
+        assert_eq!(
+            ctx.render("{{> a}}"),
+            Err(Error::PartialRecursion("a".to_owned()))
+        );
+    }
+    #[test]
+    fn blocks_and_partials_share_the_depth_limit() {
+        let ctx = Context::new()
+            .with_define(Variable::single("a"), "x")
+            .with_partial(
+                "a",
+                "{{#if a}}".repeat(8) + "{{> a}}" + &"{{/if}}".repeat(8),
+            );
+        assert_eq!(
+            ctx.render("{{> a}}"),
+            Err(Error::PartialRecursion("a".to_owned()))
+        );
+        let ctx = ctx
+            .with_partial("b", "{{#if a}}x{{/if}}")
+            .with_depth_limit(2);
+        assert_eq!(ctx.render("{{> b}}"), Ok("x".to_owned()));
+        for template in [
+            "{{#if a}}{{> b}}{{/if}}",
+            "{{#if a}}{{#if a}}{{> b}}{{/if}}{{/if}}",
+        ] {
+            assert_eq!(
+                ctx.render(template),
+                Err(Error::PartialRecursion("b".to_owned()))
+            );
+        }
+        assert_eq!(
+            ctx.render("x\n{{#if a}}{{#if a}}{{#if a}}{{/if}}{{/if}}{{/if}}"),
+            Err(Error::NestedTooDeeply {
+                offset: Location::new(18, 1)
+            })
+        );
+    }
+    #[test]
+    fn depth_limit_is_configurable() {
+        let mut ctx = Context::new()
+            .with_partial("a", "a{{> b}}")
+            .with_partial("b", "b");
+        assert_eq!(ctx.render("{{> a}}"), Ok("ab".to_owned()));
+        ctx.set_depth_limit(1);
+        assert_eq!(
+            ctx.render("{{> a}}"),
+            Err(Error::PartialRecursion("b".to_owned()))
+        );
+    }
+    #[test]
+    fn comments_are_stripped_from_output() {
+        let ctx = Context::new().with_define(Variable::single("a"), "b");
+        assert_eq!(
+            ctx.render("x{{! note }}{{a}}{{!-- }} --}}y"),
+            Ok("xby".to_owned())
+        );
+    }
+    #[test]
+    fn escaped_templates_render_literally() {
+        let ctx = Context::new().with_define(Variable::single("a"), "b");
+        assert_eq!(
+            ctx.render(r"\{{a}} {{a}} \\{{a}}"),
+            Ok(r"{{a}} b \b".to_owned())
+        );
+    }
+    #[test]
+    fn render_to_writer_writes_expanded_template() {
+        let ctx = Context::new().with_define(Variable::single("a"), "b");
+        let mut out = Vec::new();
+        ctx.render_to_writer("x {{ a }} y", &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "x b y");
+    }
+    #[test]
+    fn render_tokens_renders_chained_fragments() {
+        let ctx = Context::new()
+            .with_define(Variable::single("name"), "world")
+            .with_define(Variable::single("items"), vec!["a", "b"]);
+        let tokens = Tokenize::new("hello {{ name }}")
+            .map(Result::unwrap)
+            .chain([parse::Token::Str(", ")])
+            .chain(
+                Tokenize::new("{{#each items}}{{this}}{{/each}}{{#if nope}}x{{/if}}")
+                    .map(Result::unwrap),
+            );
+        assert_eq!(ctx.render_tokens(tokens), Ok("hello world, ab".to_owned()));
+        assert_eq!(
+            ctx.render_tokens([parse::Token::Variable(Variable::single("nope"))]),
+            Err(Error::MissingVariable(Variable::single("nope")))
+        );
+    }
+    #[test]
+    fn render_tokens_treats_invalid_number_operands_as_not_comparable() {
+        let ctx = Context::new()
+            .with_define(Variable::single("count"), "42")
+            .with_numeric_coercion(true);
+        let block = parse::Token::Block {
+            condition: Variable::single("count"),
+            comparison: Some(parse::Comparison {
+                op: parse::CompareOp::Gt,
+                rhs: parse::Operand::Number("ten"),
+            }),
+            then: vec![parse::Token::Str("yes")],
+            otherwise: vec![parse::Token::Str("no")],
+        };
+        assert_eq!(ctx.render_tokens([block]), Ok("no".to_owned()));
+    }
+    #[test]
+    fn render_to_writer_reports_write_failures_as_io_errors() {
+        let ctx = Context::new().with_define(Variable::single("a"), "b");
+        let mut buf = [0u8; 3];
+        let mut out = &mut buf[..];
+        let r = ctx.render_to_writer("x {{ a }} y", &mut out);
+        assert_eq!(
+            r,
+            Err(Error::Io(std::io::ErrorKind::WriteZero.into())),
+            "writer errors are not template errors"
+        );
+        assert_eq!(&buf, b"x b", "output up to the failure is kept");
+    }
+    #[test]
+    fn render_to_writer_reports_template_errors_before_writing_bad_token() {
+        let ctx = Context::new();
+        let mut out = Vec::new();
+        assert_eq!(
+            ctx.render_to_writer("x {{ a }}", &mut out),
+            Err(Error::MissingVariable(Variable::single("a")))
+        );
+        assert_eq!(out, b"x ");
+    }
+    #[test]
+    fn flat_and_structured_defines_resolve_the_same() {
+        let template = "{{ user.name }} <{{ user.contact.email }}>";
+        let flat = Context::new()
+            .with_define("user.name".parse().unwrap(), "ann")
+            .with_define("user.contact.email".parse().unwrap(), "a@example.com");
+        let structured = Context::new().with_define(
+            Variable::single("user"),
+            Object::new().with_property("name", "ann").with_property(
+                "contact",
+                Object::new().with_property("email", "a@example.com"),
+            ),
+        );
+        let mixed = Context::new()
+            .with_define("user.contact.email".parse().unwrap(), "a@example.com")
+            .with_define(
+                Variable::single("user"),
+                Object::new().with_property("name", "ann"),
+            );
+        let expected = Ok("ann <a@example.com>".to_owned());
+        assert_eq!(flat.render(template), expected);
+        assert_eq!(structured.render(template), expected);
+        assert_eq!(mixed.render(template), expected);
+        assert_eq!(flat, structured);
+        assert_eq!(flat, mixed);
+    }
+    #[test]
+    fn overlapping_defines_prefer_the_later_one() {
+        let ctx = Context::new()
+            .with_define("a.b".parse().unwrap(), "old")
+            .with_define(
+                Variable::single("a"),
+                Object::new().with_property("b", Object::new().with_property("c", "new")),
+            )
+            .with_define("x.y".parse().unwrap(), "gone")
+            .with_define(Variable::single("x"), "replaced");
+        assert_eq!(ctx.render("{{a.b.c}} {{x}}"), Ok("new replaced".to_owned()));
+    }
+    #[test]
     fn double_defintition_should_overwrite() {
         let ctx = Context::new()
         .with_define(Variable::single("a"), "b")
@@ -9,34 +9,166 @@ mod context;
 pub mod parse;
 mod value;
 
-pub use context::{Context, Error};
-pub use value::{Object, Value};
+pub use context::{Context, Error, EscapeMode};
 #[cfg(feature = "macros")]
 pub use handybars_macros::handybars_value;
+pub use value::{Object, Value};
 
 use crate::parse::{str_from_utf8, ErrorKind};
 
 type VariableEl<'a> = Cow<'a, str>;
 
+/// A single part of a [`Variable`] path
+///
+/// Parsing a variable turns any segment made up only of digits (without leading zeros)
+/// into an [`Index`](Segment::Index), everything else is a [`Key`](Segment::Key).
+/// When resolved against an object an index acts as the key with the same digits.
+///
+/// ```
+/// # use handybars::{Segment, Variable};
+/// let var: Variable = "items.0.name".parse().unwrap();
+/// assert_eq!(
+///     var.segments().collect::<Vec<_>>(),
+///     [&Segment::from("items"), &Segment::Index(0), &Segment::from("name")]
+/// );
+/// ```
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Segment<'a> {
+    /// Named property
+    Key(Cow<'a, str>),
+    /// Numeric position
+    Index(usize),
+}
+impl<'a> Segment<'a> {
+    /// Convert a segment into one which owns its key
+    #[must_use]
+    pub fn into_owned(self) -> Segment<'static> {
+        match self {
+            Segment::Key(k) => Segment::Key(Cow::Owned(k.into_owned())),
+            Segment::Index(i) => Segment::Index(i),
+        }
+    }
+    #[allow(missing_docs)]
+    #[must_use]
+    pub fn as_key(&self) -> Option<&str> {
+        if let Self::Key(k) = self {
+            Some(k)
+        } else {
+            None
+        }
+    }
+    #[allow(missing_docs)]
+    #[must_use]
+    pub fn as_index(&self) -> Option<usize> {
+        if let Self::Index(i) = self {
+            Some(*i)
+        } else {
+            None
+        }
+    }
+    /// Key to use when looking this segment up by name
+    pub(crate) fn to_key(&self) -> Cow<'_, str> {
+        match self {
+            Segment::Key(k) => Cow::Borrowed(k),
+            Segment::Index(i) => Cow::Owned(i.to_string()),
+        }
+    }
+    fn into_key(self) -> Cow<'a, str> {
+        match self {
+            Segment::Key(k) => k,
+            Segment::Index(i) => Cow::Owned(i.to_string()),
+        }
+    }
+    fn is_empty(&self) -> bool {
+        matches!(self, Segment::Key(k) if k.is_empty())
+    }
+    /// Length in bytes when displayed
+    fn len(&self) -> usize {
+        match self {
+            Segment::Key(k) => k.len(),
+            Segment::Index(i) => i.checked_ilog10().unwrap_or(0) as usize + 1,
+        }
+    }
+    /// Whether this segment displays as `s`
+    fn displays_as(&self, s: &str) -> bool {
+        match self {
+            Segment::Key(k) => k == s,
+            Segment::Index(i) => {
+                s.bytes().all(|c| c.is_ascii_digit())
+                    && (s == "0" || !s.starts_with('0'))
+                    && s.parse() == Ok(*i)
+            }
+        }
+    }
+    /// Segment for a property name, `None` if it can't be part of a variable path
+    fn property(name: &str) -> Option<Segment<'static>> {
+        (!name.is_empty() && name.bytes().all(parse::is_valid_identifier_ch))
+            .then(|| Segment::parsed(name))
+    }
+    /// Segment for a part of a parsed path, see the type level docs
+    fn parsed(s: &str) -> Segment<'static> {
+        Segment::from_part(Cow::Borrowed(s)).into_owned()
+    }
+    /// Segment for a part of a path, digits without leading zeros are an index
+    fn from_part(part: Cow<'a, str>) -> Segment<'a> {
+        let canonical =
+            part.bytes().all(|c| c.is_ascii_digit()) && (part == "0" || !part.starts_with('0'));
+        match part.parse() {
+            Ok(i) if canonical => Segment::Index(i),
+            _ => Segment::Key(part),
+        }
+    }
+}
+impl std::fmt::Display for Segment<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Segment::Key(k) => f.write_str(k),
+            Segment::Index(i) => write!(f, "{i}"),
+        }
+    }
+}
+/// Strings become segments the same way as when parsing, so `"0"` is [`Segment::Index`]
+impl<'a> From<Cow<'a, str>> for Segment<'a> {
+    fn from(value: Cow<'a, str>) -> Self {
+        Self::from_part(value)
+    }
+}
+impl<'a> From<&'a str> for Segment<'a> {
+    fn from(value: &'a str) -> Self {
+        Self::from_part(value.into())
+    }
+}
+impl From<String> for Segment<'static> {
+    fn from(value: String) -> Self {
+        Self::from_part(value.into())
+    }
+}
+impl From<usize> for Segment<'_> {
+    fn from(value: usize) -> Self {
+        Self::Index(value)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 enum VariableInner<'a> {
-    Segments(Vec<VariableEl<'a>>),
-    Single(VariableEl<'a>),
+    Segments(Vec<Segment<'a>>),
+    Single(Segment<'a>),
 }
 impl VariableInner<'_> {
     fn into_owned(self) -> VariableInner<'static> {
         match self {
             VariableInner::Segments(s) => {
-                VariableInner::Segments(s.into_iter().map(|s| Cow::Owned(s.into_owned())).collect())
+                VariableInner::Segments(s.into_iter().map(Segment::into_owned).collect())
             }
-            VariableInner::Single(s) => VariableInner::Single(Cow::Owned(s.into_owned())),
+            VariableInner::Single(s) => VariableInner::Single(s.into_owned()),
         }
     }
 }
 
 /// Variable that can be used in templates
 ///
-/// A variable is a series of non-empty strings seperated by `.`
+/// A variable is a series of non-empty strings seperated by `.`, segments which are
+/// numbers are stored as indexes (see [`Segment`])
 ///
 /// The lifetime specifier is used to allow variables
 /// which do not own all of their parts. To get a variable
@@ -73,20 +205,55 @@ impl<'a> Variable<'a> {
             VariableInner::Single(s) => s.len(),
         }
     }
+    /// Iterate over the segments of the variable
+    pub fn segments(&self) -> impl Iterator<Item = &Segment<'a>> {
+        match &self.inner {
+            VariableInner::Segments(s) => s.as_slice(),
+            VariableInner::Single(s) => std::slice::from_ref(s),
+        }
+        .iter()
+    }
+    /// Each segment of the variable as an owned string, indexes are converted to their number
+    ///
+    /// ```
+    /// # use handybars::Variable;
+    /// let var: Variable = "items.0".parse().unwrap();
+    /// assert_eq!(var.components_owned(), ["items", "0"]);
+    /// ```
+    #[must_use]
+    pub fn components_owned(&self) -> Vec<String> {
+        self.segments().map(ToString::to_string).collect()
+    }
+    /// The variable as a full tag using `open` and `close` as delimiters
+    ///
+    /// [`Display`](std::fmt::Display) only gives the name, this is useful when generating
+    /// templates which use [custom delimiters](parse::Delimiters)
+    ///
+    /// ```
+    /// # use handybars::Variable;
+    /// let var: Variable = "user.name".parse().unwrap();
+    /// assert_eq!(var.display_with_delimiters("{{", "}}"), "{{ user.name }}");
+    /// ```
     #[must_use]
-    fn from_segments(segments: Vec<VariableEl<'a>>) -> Self {
+    pub fn display_with_delimiters(&self, open: &str, close: &str) -> String {
+        format!("{open} {self} {close}")
+    }
+    #[must_use]
+    fn from_segments(segments: Vec<Segment<'a>>) -> Self {
         Self {
             inner: VariableInner::Segments(segments),
         }
     }
     #[must_use]
-    fn single_unchecked(name: impl Into<VariableEl<'a>>) -> Self {
+    fn single_unchecked(name: impl Into<Segment<'a>>) -> Self {
         Self {
             inner: VariableInner::Single(name.into()),
         }
     }
     /// Construct a variable out of a single element
     ///
+    /// Like [`from_parts`](Variable::from_parts) a numeric element is an index
+    ///
     /// Panics: If given a string which contains `.` or `var` is an empty string
     #[must_use]
     pub fn single(var: impl Into<VariableEl<'a>>) -> Self {
@@ -99,10 +266,18 @@ impl<'a> Variable<'a> {
             !val.is_empty(),
             "cannot construct a variable with an empty string"
         );
-        Self::single_unchecked(val)
+        Self::single_unchecked(Segment::from_part(val))
     }
     /// Construct a variable from parts individually
     ///
+    /// Parts are turned into segments the same way as when parsing, so numeric parts are
+    /// indexes
+    ///
+    /// ```
+    /// # use handybars::Variable;
+    /// assert_eq!(Variable::from_parts(["items", "0"]), "items.0".parse::<Variable>().unwrap());
+    /// ```
+    ///
     /// Panics: If any string in `parts` is empty or if `parts` has no elements
     #[must_use]
     pub fn from_parts(parts: impl IntoIterator<Item = impl Into<VariableEl<'a>>>) -> Self {
@@ -119,7 +294,7 @@ impl<'a> Variable<'a> {
                     [fst.unwrap(), snd]
                         .into_iter()
                         .chain(parts)
-                        .map(|p| p.into())
+                        .map(|p| Segment::from(p.into()))
                         .inspect(|s| assert!(!s.is_empty(), "variable part cannot be empty"))
                         .collect(),
                 ),
@@ -128,6 +303,104 @@ impl<'a> Variable<'a> {
             Self::single(fst.unwrap())
         }
     }
+    /// Construct a variable from parts which may be indexes
+    ///
+    /// Unlike [`from_parts`](Variable::from_parts) this accepts `usize` parts
+    ///
+    /// ```
+    /// # use handybars::{Segment, Variable};
+    /// let var = Variable::from_parts_indexed([Segment::from("items"), 0.into()]);
+    /// assert_eq!(var, "items.0".parse::<Variable>().unwrap());
+    /// ```
+    ///
+    /// Panics: If any key in `parts` is empty or if `parts` has no elements
+    #[must_use]
+    pub fn from_parts_indexed(parts: impl IntoIterator<Item = impl Into<Segment<'a>>>) -> Self {
+        let mut parts = parts
+            .into_iter()
+            .map(Into::into)
+            .inspect(|s: &Segment| assert!(!s.is_empty(), "variable part cannot be empty"))
+            .collect::<Vec<_>>();
+        assert!(
+            !parts.is_empty(),
+            "iterator passed to Variable::from_parts_indexed has no elements"
+        );
+        if parts.len() == 1 {
+            Self::single_unchecked(parts.pop().unwrap())
+        } else {
+            Self::from_segments(parts)
+        }
+    }
+    /// Add a segment to the end of the variable
+    ///
+    /// ```
+    /// # use handybars::Variable;
+    /// let mut var = Variable::single("a");
+    /// var.push_segment("b");
+    /// var.push_segment(0);
+    /// assert_eq!(&var.to_string(), "a.b.0");
+    /// ```
+    ///
+    /// Panics: If `seg` is an empty string or contains `.`
+    pub fn push_segment(&mut self, seg: impl Into<Segment<'a>>) -> &mut Self {
+        let seg = seg.into();
+        assert!(!seg.is_empty(), "variable part cannot be empty");
+        assert!(
+            !seg.as_key().is_some_and(|k| k.contains('.')),
+            "variable part cannot contain dot separator"
+        );
+        self.inner = match std::mem::replace(&mut self.inner, VariableInner::Segments(Vec::new())) {
+            VariableInner::Segments(mut s) => {
+                s.push(seg);
+                VariableInner::Segments(s)
+            }
+            VariableInner::Single(first) => VariableInner::Segments(vec![first, seg]),
+        };
+        self
+    }
+    /// The variable with its last segment removed, `None` if it only has one segment
+    ///
+    /// ```
+    /// # use handybars::Variable;
+    /// let var: Variable = "a.b.c".parse().unwrap();
+    /// assert_eq!(var.parent(), Some("a.b".parse().unwrap()));
+    /// assert_eq!(Variable::single("a").parent(), None);
+    /// ```
+    #[must_use]
+    pub fn parent(&self) -> Option<Variable<'a>> {
+        match &self.inner {
+            VariableInner::Segments(s) => Some(match &s[..s.len() - 1] {
+                [first] => Self::single_unchecked(first.clone()),
+                rest => Self::from_segments(rest.to_vec()),
+            }),
+            VariableInner::Single(_) => None,
+        }
+    }
+    /// Move a variable from under `from` to under `to`
+    ///
+    /// Returns `None` if `self` does not start with all the segments of `from`. If `self` is
+    /// `from` then this is just `to`
+    ///
+    /// ```
+    /// # use handybars::Variable;
+    /// let var: Variable = "old.sub.x".parse().unwrap();
+    /// let rebased = var.rebase(&Variable::single("old"), &Variable::single("new"));
+    /// assert_eq!(rebased, Some("new.sub.x".parse().unwrap()));
+    /// ```
+    #[must_use]
+    pub fn rebase(&self, from: &Variable<'_>, to: &Variable<'a>) -> Option<Variable<'a>> {
+        let mut segments = self.segments();
+        for prefix in from.segments() {
+            if segments.next()? != prefix {
+                return None;
+            }
+        }
+        let mut rebased = to.clone();
+        for seg in segments {
+            rebased.push_segment(seg.clone());
+        }
+        Some(rebased)
+    }
     /// Join together two variables
     ///
     /// ```
@@ -158,19 +431,85 @@ impl<'a> Variable<'a> {
         }
     }
 }
+/// Displays the variable in its dotted form, this is the inverse of [`FromStr`]
+///
+/// ```
+/// # use handybars::Variable;
+/// let var: Variable = "a.0.c".parse().unwrap();
+/// assert_eq!(var.to_string(), "a.0.c");
+/// assert_eq!(var.to_string().parse::<Variable>(), Ok(var));
+/// ```
 impl std::fmt::Display for Variable<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self.inner {
-            VariableInner::Segments(s) => f.write_str(&s.join(".")),
-            VariableInner::Single(s) => f.write_str(s),
+        for (i, seg) in self.segments().enumerate() {
+            if i != 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "{seg}")?;
         }
+        Ok(())
     }
 }
 
+/// Compares against the dotted form of the variable, as given by its [`Display`](std::fmt::Display)
+///
+/// ```
+/// # use handybars::Variable;
+/// let var: Variable = "a.0.c".parse().unwrap();
+/// assert_eq!(var, "a.0.c");
+/// assert_ne!(var, "a.00.c");
+/// ```
+impl PartialEq<str> for Variable<'_> {
+    fn eq(&self, other: &str) -> bool {
+        let mut parts = other.split('.');
+        self.segments()
+            .all(|seg| parts.next().is_some_and(|part| seg.displays_as(part)))
+            && parts.next().is_none()
+    }
+}
+impl PartialEq<&str> for Variable<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+impl PartialEq<Variable<'_>> for str {
+    fn eq(&self, other: &Variable<'_>) -> bool {
+        other == self
+    }
+}
+impl PartialEq<Variable<'_>> for &str {
+    fn eq(&self, other: &Variable<'_>) -> bool {
+        other == *self
+    }
+}
+
+/// Variables set by `{{#each}}`, these are the only names which may start with `@`
+const SPECIAL_VARIABLES: [&str; 4] = ["@index", "@key", "@first", "@last"];
+
 fn parse_with_terminator(
     s: &str,
     error_if_invalid: bool,
 ) -> Result<Variable<'static>, parse::Error> {
+    if let Some(rest) = s.strip_prefix('@').filter(|rest| {
+        rest.bytes()
+            .next()
+            .is_some_and(parse::is_valid_identifier_ch)
+    }) {
+        // special variables (`@index`, `@key`, ...) are the only place `@` may appear, an `@`
+        // on its own isn't a name at all
+        let mut var =
+            parse_with_terminator(rest, error_if_invalid).map_err(|e| e.add_offset((1, 0), 1))?;
+        let first = match &mut var.inner {
+            VariableInner::Segments(s) => &mut s[0],
+            VariableInner::Single(s) => s,
+        };
+        let name = format!("@{first}");
+        if !SPECIAL_VARIABLES.contains(&name.as_str()) {
+            return Err(parse::Error::new((0, 0), 0, ErrorKind::ReservedName));
+        }
+        *first = Segment::Key(Cow::Owned(name));
+        return Ok(var);
+    }
     let chars = s.as_bytes();
 
     let valid_len = {
@@ -186,6 +525,7 @@ fn parse_with_terminator(
     if error_if_invalid && valid_len != s.len() {
         return Err(parse::Error::new(
             (valid_len, 0),
+            valid_len,
             parse::ErrorKind::InvalidCharacter {
                 token: chars[valid_len],
             },
@@ -194,6 +534,7 @@ fn parse_with_terminator(
     if valid_len == 0 {
         return Err(parse::Error::new(
             (0, 0),
+            0,
             parse::ErrorKind::EmptyVariableSegment,
         ));
     }
@@ -220,11 +561,15 @@ fn parse_with_terminator(
                     }
                     (found_space || len == valid_len) && found_dot
                 } {
-                    return Err(parse::Error::new((len, 0), parse::ErrorKind::SpaceInPath));
+                    return Err(parse::Error::new(
+                        (len, 0),
+                        len,
+                        parse::ErrorKind::SpaceInPath,
+                    ));
                 } else if len == valid_len {
-                    Variable::single_unchecked(seg_s.to_owned())
+                    Variable::single_unchecked(Segment::parsed(seg_s))
                 } else {
-                    let mut segments = vec![Cow::Owned(seg_s.to_owned())];
+                    let mut segments = vec![Segment::parsed(seg_s)];
                     let mut head = seg_s.len();
                     let mut segs = loop {
                         if head == valid_len || chars[head] as char == ' ' {
@@ -235,6 +580,7 @@ fn parse_with_terminator(
                             if parse_with_terminator(str_from_utf8(&chars[head..]), false).is_ok() {
                                 return Err(parse::Error::new(
                                     (head, 0),
+                                    head,
                                     ErrorKind::TooManyVariablesInBlock,
                                 ));
                             }
@@ -243,9 +589,17 @@ fn parse_with_terminator(
                         if chars[head] as char == '.' {
                             let orig_head = head;
                             head += 1;
+                            if chars.get(head) == Some(&b'\n') {
+                                return Err(parse::Error::new(
+                                    (head, 0),
+                                    head,
+                                    ErrorKind::NewlineInVariableSegment,
+                                ));
+                            }
                             if head == valid_len || chars[head] as char == ' ' {
                                 return Err(parse::Error::new(
                                     (orig_head, 0),
+                                    orig_head,
                                     ErrorKind::EmptyVariableSegment,
                                 ));
                             }
@@ -253,10 +607,10 @@ fn parse_with_terminator(
                         }
                         assert!(head < s.len());
                         match parse::try_parse_variable_segment(&chars[head..]) {
-                            Err(e) => return Err(e.add_offset((head, 0))),
+                            Err(e) => return Err(e.add_offset((head, 0), head)),
                             Ok(seg) => {
                                 let len = seg.len();
-                                segments.push(Cow::Owned(parse::str_from_utf8(seg).to_owned()));
+                                segments.push(Segment::parsed(parse::str_from_utf8(seg)));
                                 head += len;
                             }
                         }
@@ -291,7 +645,7 @@ mod tests {
         let var = Variable::from_str("a .b");
         assert_eq!(
             var,
-            Err(parse::Error::new((1, 0), parse::ErrorKind::SpaceInPath))
+            Err(parse::Error::new((1, 0), 1, parse::ErrorKind::SpaceInPath))
         );
     }
     #[test]
@@ -310,6 +664,119 @@ mod tests {
         );
     }
     #[test]
+    fn variable_compares_equal_to_its_dotted_form() {
+        let var = Variable::from_str("a.b.10").unwrap();
+        assert_eq!(var, "a.b.10");
+        assert_eq!("a.b.10", var);
+        assert_eq!(*"a.b.10", var);
+        for other in [
+            "a.b", "a.b.10.c", "a.b.010", "a.b.+10", "A.b.10", "a.b.10.", "",
+        ] {
+            assert_ne!(var, other);
+        }
+        let keyed = Variable::from_parts(["x", "01"]);
+        assert_eq!(keyed, "x.01");
+        assert_eq!(Variable::single("x"), "x");
+        assert_ne!(Variable::single("x"), "x.");
+    }
+    proptest! {
+        #[test]
+        fn variable_eq_str_matches_display(s in "[a-z0-9]{1,3}(\\.[a-z0-9]{1,3}){0,3}") {
+            let var = Variable::from_str(&s).unwrap();
+            prop_assert_eq!(&var, s.as_str());
+            let displayed = var.to_string();
+            prop_assert_eq!(&var, displayed.as_str());
+        }
+    }
+    #[test]
+    fn rebase_moves_variables_under_prefix() {
+        let var = |s: &str| Variable::from_str(s).unwrap();
+        assert_eq!(
+            var("old.sub.x").rebase(&var("old"), &var("new.ns")),
+            Some(var("new.ns.sub.x"))
+        );
+        assert_eq!(
+            var("a.b.0.c").rebase(&var("a.b.0"), &var("z")),
+            Some(var("z.c"))
+        );
+        assert_eq!(
+            var("a.0.b").rebase(
+                &Variable::from_parts_indexed([Segment::from("a"), Segment::from("0")]),
+                &var("new")
+            ),
+            Some(var("new.b"))
+        );
+        assert_eq!(
+            var("old").rebase(&var("old"), &var("new")),
+            Some(var("new"))
+        );
+        assert_eq!(
+            var("old.sub").rebase(&var("old.sub"), &var("new.ns")),
+            Some(var("new.ns"))
+        );
+    }
+    #[test]
+    fn rebase_outside_prefix_is_none() {
+        let var = |s: &str| Variable::from_str(s).unwrap();
+        assert_eq!(var("other.x").rebase(&var("old"), &var("new")), None);
+        assert_eq!(var("older.x").rebase(&var("old"), &var("new")), None);
+        assert_eq!(var("old").rebase(&var("old.x"), &var("new")), None);
+    }
+    #[test]
+    fn display_with_custom_delimiters_round_trips() {
+        let var = Variable::from_str("user.names.0").unwrap();
+        let tag = var.display_with_delimiters("<%", "%>");
+        assert_eq!(tag, "<% user.names.0 %>");
+        assert_eq!(
+            parse::tokenize_with(&tag, parse::Delimiters::new("<%", "%>")),
+            Ok(vec![parse::Token::Variable(var)])
+        );
+    }
+    #[test]
+    fn components_owned_lists_every_segment() {
+        assert_eq!(
+            Variable::from_str("a.b.12.c").unwrap().components_owned(),
+            vec![
+                "a".to_owned(),
+                "b".to_owned(),
+                "12".to_owned(),
+                "c".to_owned()
+            ]
+        );
+        assert_eq!(
+            Variable::single("a").components_owned(),
+            vec!["a".to_owned()]
+        );
+    }
+    #[test]
+    fn push_segment_promotes_single_to_segments() {
+        let mut var = Variable::single("a");
+        var.push_segment("b");
+        assert_eq!(
+            var.inner,
+            VariableInner::Segments(vec!["a".into(), "b".into()])
+        );
+        var.push_segment(Cow::Borrowed("c")).push_segment(2);
+        assert_eq!(var, Variable::from_str("a.b.c.2").unwrap());
+    }
+    #[test]
+    fn parent_removes_last_segment() {
+        let var = Variable::from_str("a.b.c").unwrap();
+        let parent = var.parent().unwrap();
+        assert_eq!(parent, Variable::from_parts(["a", "b"]));
+        let grandparent = parent.parent().unwrap();
+        assert_eq!(grandparent.inner, VariableInner::Single("a".into()));
+        assert_eq!(grandparent.parent(), None);
+        let mut pushed = grandparent.clone();
+        pushed.push_segment("z");
+        assert_eq!(pushed.parent(), Some(grandparent));
+    }
+    #[test]
+    #[should_panic]
+    fn push_segment_with_path_fails() {
+        Variable::single("a").push_segment("b.c");
+    }
+    #[test]
     #[should_panic]
     fn constructing_single_variable_with_path_fails() {
         let _ = Variable::single("a.b");
@@ -332,6 +799,7 @@ mod tests {
             Variable::from_str("x."),
             Err(parse::Error::new(
                 (1, 0),
+                1,
                 parse::ErrorKind::EmptyVariableSegment
             ))
         );
@@ -343,6 +811,7 @@ mod tests {
             Variable::from_str("a.b c.d"),
             Err(parse::Error::new(
                 (4, 0),
+                4,
                 ErrorKind::TooManyVariablesInBlock
             ))
         );
@@ -353,7 +822,9 @@ mod tests {
         let var: Variable = "x.y".parse().unwrap();
         assert_eq!(var, Variable::from_parts(["x", "y"]));
     }
-    fn run_parsing_variable_test(input: &str) -> (Result<Variable, parse::Error>, Variable) {
+    fn run_parsing_variable_test(
+        input: &str,
+    ) -> (Result<Variable<'_>, parse::Error>, Variable<'_>) {
         let var = Variable::from_str(input);
         let split = input
             .split(' ')
@@ -362,14 +833,93 @@ mod tests {
             .trim_end_matches('}')
             .split('.')
             .collect::<Vec<_>>();
-        let expected = if split.len() == 1 {
-            Variable::single_unchecked(split[0])
-        } else {
-            Variable::from_parts(split)
-        };
+        let expected = Variable::from_parts_indexed(split.into_iter().map(Segment::parsed));
         (var, expected)
     }
     #[test]
+    fn parsing_variable_with_numeric_segment_creates_index() {
+        let var: Variable = "items.0.name".parse().unwrap();
+        assert_eq!(
+            var.inner,
+            VariableInner::Segments(vec!["items".into(), Segment::Index(0), "name".into()])
+        );
+        assert_eq!(var.len(), "items.0.name".len());
+    }
+    #[test]
+    fn parsing_variable_with_leading_zero_keeps_key() {
+        let var: Variable = "a.01".parse().unwrap();
+        assert_eq!(var, Variable::from_parts(["a", "01"]));
+        assert_eq!(var.to_string(), "a.01");
+    }
+    #[test]
+    fn from_parts_turns_numeric_strings_into_indexes() {
+        let var = Variable::from_parts(["a", "0", "01"]);
+        assert_eq!(
+            var.inner,
+            VariableInner::Segments(vec!["a".into(), Segment::Index(0), "01".into()])
+        );
+        assert_eq!(var, Variable::from_str("a.0.01").unwrap());
+    }
+    #[test]
+    fn numeric_string_segments_are_indexes() {
+        let single = Variable::single("0");
+        assert_eq!(single.to_string().parse(), Ok(single.clone()));
+        assert_eq!(single, Variable::single_unchecked(0));
+        let mut pushed = Variable::single("a");
+        pushed.push_segment("0").push_segment(String::from("1"));
+        assert_eq!(pushed, Variable::from_str("a.0.1").unwrap());
+        assert_eq!(
+            Variable::from_parts_indexed(["a", "0"]),
+            Variable::from_str("a.0").unwrap()
+        );
+    }
+    #[test]
+    fn from_parts_indexed_mixes_keys_and_indexes() {
+        let var = Variable::from_parts_indexed([Segment::from("a"), 10.into(), "b".into()]);
+        assert_eq!(var, Variable::from_str("a.10.b").unwrap());
+        assert_eq!(var.len(), 6);
+    }
+    #[test]
+    fn displaying_single_and_segmented_variables() {
+        assert_eq!(Variable::single("x").to_string(), "x");
+        assert_eq!(Variable::from_parts(["a", "b", "c"]).to_string(), "a.b.c");
+        assert_eq!(
+            Variable::from_parts_indexed([Segment::from("a"), 12.into()]).to_string(),
+            "a.12"
+        );
+    }
+    #[test]
+    fn parsing_special_variable_keeps_at_prefix() {
+        let var: Variable = "@index".parse().unwrap();
+        assert_eq!(var, Variable::single("@index"));
+        assert_eq!(var.len(), 6);
+        assert_eq!(
+            Variable::from_str("@index.@b"),
+            Err(parse::Error::new(
+                (7, 0),
+                7,
+                ErrorKind::InvalidCharacter { token: b'@' }
+            ))
+        );
+    }
+    #[test]
+    fn parsing_unknown_special_variable_fails() {
+        for name in ["@indx", "@", "@0", "@index_", "@Index"] {
+            assert!(Variable::from_str(name).is_err(), "{name}");
+        }
+        assert_eq!(
+            Variable::from_str("@indx"),
+            Err(parse::Error::new((0, 0), 0, ErrorKind::ReservedName))
+        );
+        assert_eq!(
+            Variable::from_str("@indx.a"),
+            Err(parse::Error::new((0, 0), 0, ErrorKind::ReservedName))
+        );
+        for name in SPECIAL_VARIABLES {
+            assert_eq!(Variable::from_str(name), Ok(Variable::single(name)));
+        }
+    }
+    #[test]
     fn a_variable_constructed_with_one_sized_vec_becomes_single() {
         assert_eq!(Variable::from_parts(["a"]), Variable::single("a"));
     }
@@ -387,6 +937,12 @@ mod tests {
             prop_assert_eq!(var, Ok(expected));
         }
         #[test]
+        fn displaying_a_parsed_variable_round_trips(input in r"[[:alpha:]][[[:alpha:]]\d]*(\.[[[:alpha:]]\d]+)*") {
+            let var = Variable::from_str(&input).unwrap();
+            prop_assert_eq!(&var.to_string(), &input);
+            prop_assert_eq!(var.to_string().parse::<Variable>(), Ok(var));
+        }
+        #[test]
         fn parsing_variable_from_unicode_works(input in r"([[[:alpha:]]~~[\p{Alphabetic}\d]])+(\.([[[:alpha:]]~~[\p{Alphabetic}\d]])+)*[ ]*") {
             let (var, expected) = run_parsing_variable_test(&input);
             prop_assert_eq!(var, Ok(expected));
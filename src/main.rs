@@ -1,61 +1,96 @@
 use std::{
+    fmt::Display,
     fs::File,
     io::{BufReader, Read},
     process::exit,
-    str::Split,
 };
 
-use handybars::Context;
+use handybars::{Context, Variable};
 
 fn resolve_input(path: Option<&String>) -> Box<dyn Read> {
     match path.map(|s| s.as_str()) {
         None | Some("-") => Box::new(std::io::stdin().lock()),
-        Some(v) => Box::new(BufReader::new(File::open(v).expect("failed to open input"))),
+        Some(v) => {
+            Box::new(BufReader::new(File::open(v).unwrap_or_else(|e| {
+                fail(format_args!("failed to open '{v}': {e}"))
+            })))
+        }
     }
 }
-fn parse_defines(args: &[String]) -> Vec<(String, String)> {
+/// Values given to a flag, either as `--flag value` or `--flag=value`
+fn flag_values<'a>(args: &'a [String], names: &[&str]) -> Vec<&'a str> {
     let mut out = Vec::new();
-    let mut expecting_define = false;
-    let define_prefixs = ["--define", "-D"];
-    let mut push_define = |mut input: Split<char>| {
-        out.push((
-            input.next().expect("expected value after =").to_owned(),
-            input
-                .next()
-                .expect("expected define of the form X=Y")
-                .to_owned(),
-        ));
-    };
-    for arg in args {
-        if expecting_define {
-            expecting_define = false;
-            let input = arg.split('=');
-            push_define(input);
-        }
-        if let Some(has_equals) = define_prefixs.iter().find_map(|p| {
-            if arg.starts_with(p) {
-                Some(arg.len() > p.len() && arg.as_bytes()[p.len()] as char == '=')
-            } else {
-                None
-            }
-        }) {
-            expecting_define = !has_equals;
-            if has_equals {
-                let mut input = arg.split('=');
-                input.next();
-                push_define(input);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        for name in names {
+            if arg == name {
+                match args.next() {
+                    Some(value) => out.push(value.as_str()),
+                    None => fail(format_args!("expected a value after {name}")),
+                }
+            } else if let Some(value) = arg.strip_prefix(name).and_then(|v| v.strip_prefix('=')) {
+                out.push(value);
             }
         }
     }
     out
 }
+fn parse_defines(args: &[String]) -> Vec<(&str, &str)> {
+    flag_values(args, &["--define", "-D"])
+        .into_iter()
+        .map(|define| {
+            define.split_once('=').unwrap_or_else(|| {
+                fail(format_args!(
+                    "invalid define '{define}', expected the form NAME=VALUE"
+                ))
+            })
+        })
+        .collect()
+}
+/// Define every environment variable `NAME` as `env.NAME`, skipping any whose name can't be a variable
+fn define_env(ctx: &mut Context<'static>) {
+    for (name, value) in std::env::vars_os() {
+        let (Some(name), Ok(value)) = (name.to_str(), value.into_string()) else {
+            continue;
+        };
+        match format!("env.{name}").parse::<Variable>() {
+            Ok(var) if var.segments().count() == 2 => ctx.define(var, value),
+            _ => continue,
+        };
+    }
+}
+/// Define each property of the JSON object in the file at `path` as a variable, skipping any
+/// whose name can't be a variable
+fn define_data(ctx: &mut Context<'static>, path: &str) {
+    let mut input = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut input))
+        .unwrap_or_else(|e| fail(format_args!("failed to read '{path}': {e}")));
+    let properties =
+        json::parse_object(&input).unwrap_or_else(|e| fail(format_args!("{path}: {e}")));
+    for (name, value) in properties {
+        match name.parse::<Variable>() {
+            Ok(var) if var.segments().count() == 1 => ctx.define(var, value),
+            _ => continue,
+        };
+    }
+}
+fn fail(err: impl Display) -> ! {
+    eprintln!("error: {err}");
+    exit(1)
+}
 fn print_usage(path: &str) {
     print!(
         r"handybars - simple template expansion
 
-Usage: {path} [INPUT|-] {{(--define|-D)=varname=value}}*
+Usage: {path} [INPUT|-] [--env] {{--data=FILE}}* {{(--define|-D)=varname=value}}*
 
     '-' for INPUT is stdin, if INPUT is not provided it defaults to '-'
+    --env makes environment variables available as env.NAME
+    --data defines the properties of the JSON object in FILE
+
+Defines take priority over data files, which take priority over the environment. Exits with
+status 1 and prints the error to stderr if the template fails to render
 
 e.g.
 > echo '{{ hello.world }}' | {path} - --define hello.world='hello world'
@@ -70,20 +105,370 @@ fn main() {
         print_usage(&args[0]);
         exit(1);
     }
-    let defines = if args.len() > 2 {
-        parse_defines(&args[2..])
-    } else {
-        Vec::new()
+    let (path, flags) = match args.get(1) {
+        Some(arg) if arg == "-" || !arg.starts_with('-') => (Some(arg), &args[2..]),
+        _ => (None, &args[1..]),
     };
     let mut ctx = Context::new();
-    for (var, val) in defines {
-        ctx.define(var.parse().expect("failed to parse define variable"), val);
+    if flags.iter().any(|f| f == "--env") {
+        define_env(&mut ctx);
+    }
+    for path in flag_values(flags, &["--data"]) {
+        define_data(&mut ctx, path);
+    }
+    for (var, val) in parse_defines(flags) {
+        match var.parse() {
+            Ok(var) => ctx.define(var, val.to_owned()),
+            Err(e) => fail(format_args!("invalid define '{var}': {e}")),
+        };
     }
 
     let mut input = String::new();
-    resolve_input(args.get(1))
+    resolve_input(path)
         .read_to_string(&mut input)
-        .expect("failed to read input");
-    let output = ctx.render(&input).expect("failed to render template");
+        .unwrap_or_else(|e| fail(format_args!("failed to read input: {e}")));
+    let output = ctx.render(&input).unwrap_or_else(|e| fail(e));
     print!("{}", output);
 }
+
+/// Just enough JSON to read data files
+///
+/// Numbers are kept as written, `true` and `false` become strings like they do when converting
+/// a `bool` and `null` becomes an empty string like `None`. Arrays and objects can only be nested
+/// [`MAX_DEPTH`](json::MAX_DEPTH) deep
+mod json {
+    use handybars::{Object, Value};
+
+    pub struct Error {
+        offset: usize,
+        message: &'static str,
+    }
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} at byte {}", self.message, self.offset)
+        }
+    }
+    type Result<T> = std::result::Result<T, Error>;
+
+    /// How deeply arrays and objects can be nested, including the outermost object
+    pub const MAX_DEPTH: usize = 128;
+
+    /// Parse an object, giving its properties in the order they are written
+    pub fn parse_object(input: &str) -> Result<Vec<(String, Value<'static>)>> {
+        let mut parser = Parser {
+            input,
+            pos: 0,
+            depth: 0,
+        };
+        parser.skip_whitespace();
+        if parser.peek() != Some(b'{') {
+            return parser.error("data must be a JSON object");
+        }
+        let value = parser.nested(Parser::properties)?;
+        parser.skip_whitespace();
+        if parser.pos != input.len() {
+            return parser.error("unexpected trailing characters");
+        }
+        Ok(value)
+    }
+
+    struct Parser<'a> {
+        input: &'a str,
+        pos: usize,
+        /// Number of arrays and objects `pos` is inside of
+        depth: usize,
+    }
+    impl Parser<'_> {
+        fn peek(&self) -> Option<u8> {
+            self.input.as_bytes().get(self.pos).copied()
+        }
+        fn error<T>(&self, message: &'static str) -> Result<T> {
+            Err(Error {
+                offset: self.pos,
+                message,
+            })
+        }
+        /// Parse an array or object with `f`, failing if that nests too deeply
+        fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+            if self.depth == MAX_DEPTH {
+                return self.error("arrays and objects are nested too deeply");
+            }
+            self.depth += 1;
+            let out = f(self);
+            self.depth -= 1;
+            out
+        }
+        fn skip_whitespace(&mut self) {
+            while matches!(self.peek(), Some(b' ' | b'\n' | b'\r' | b'\t')) {
+                self.pos += 1;
+            }
+        }
+        fn literal(&mut self, literal: &str, value: &'static str) -> Result<Value<'static>> {
+            if !self.input[self.pos..].starts_with(literal) {
+                return self.error("expected a value");
+            }
+            self.pos += literal.len();
+            Ok(Value::from(value))
+        }
+        fn value(&mut self) -> Result<Value<'static>> {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b'{') => self.nested(Self::object),
+                Some(b'[') => self.nested(Self::array),
+                Some(b'"') => self.string().map(Value::from),
+                Some(b't') => self.literal("true", "true"),
+                Some(b'f') => self.literal("false", "false"),
+                Some(b'n') => self.literal("null", ""),
+                Some(b'-' | b'0'..=b'9') => self.number(),
+                _ => self.error("expected a value"),
+            }
+        }
+        fn object(&mut self) -> Result<Value<'static>> {
+            let mut obj = Object::new();
+            for (name, value) in self.properties()? {
+                obj.add_property(name, value);
+            }
+            Ok(obj.into())
+        }
+        fn properties(&mut self) -> Result<Vec<(String, Value<'static>)>> {
+            self.pos += 1;
+            let mut properties = Vec::new();
+            self.skip_whitespace();
+            if self.peek() == Some(b'}') {
+                self.pos += 1;
+                return Ok(properties);
+            }
+            loop {
+                self.skip_whitespace();
+                if self.peek() != Some(b'"') {
+                    return self.error("expected a property name");
+                }
+                let start = self.pos;
+                let name = self.string()?;
+                if name.contains('.') {
+                    return Err(Error {
+                        offset: start,
+                        message: "property names may not contain '.'",
+                    });
+                }
+                self.skip_whitespace();
+                if self.peek() != Some(b':') {
+                    return self.error("expected ':'");
+                }
+                self.pos += 1;
+                properties.push((name, self.value()?));
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(b',') => self.pos += 1,
+                    Some(b'}') => {
+                        self.pos += 1;
+                        return Ok(properties);
+                    }
+                    _ => return self.error("expected ',' or '}'"),
+                }
+            }
+        }
+        fn array(&mut self) -> Result<Value<'static>> {
+            self.pos += 1;
+            let mut items = Vec::new();
+            self.skip_whitespace();
+            if self.peek() == Some(b']') {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+            loop {
+                items.push(self.value()?);
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(b',') => self.pos += 1,
+                    Some(b']') => {
+                        self.pos += 1;
+                        return Ok(Value::Array(items));
+                    }
+                    _ => return self.error("expected ',' or ']'"),
+                }
+            }
+        }
+        /// Skip over digits, giving how many there were
+        fn digits(&mut self) -> usize {
+            let start = self.pos;
+            while matches!(self.peek(), Some(b'0'..=b'9')) {
+                self.pos += 1;
+            }
+            self.pos - start
+        }
+        /// Skip over `ch` if it is next
+        fn eat(&mut self, ch: &[u8]) -> bool {
+            let found = self.peek().is_some_and(|c| ch.contains(&c));
+            if found {
+                self.pos += 1;
+            }
+            found
+        }
+        fn number(&mut self) -> Result<Value<'static>> {
+            let start = self.pos;
+            self.eat(b"-");
+            let valid = (self.eat(b"0") || self.digits() > 0)
+                && !matches!(self.peek(), Some(b'0'..=b'9'))
+                && (!self.eat(b".") || self.digits() > 0)
+                && (!self.eat(b"eE") || {
+                    self.eat(b"+-");
+                    self.digits() > 0
+                });
+            if !valid {
+                self.pos = start;
+                return self.error("invalid number");
+            }
+            Ok(Value::from(self.input[start..self.pos].to_owned()))
+        }
+        fn hex_escape(&mut self) -> Result<u32> {
+            let digits = self
+                .input
+                .get(self.pos..self.pos + 4)
+                .filter(|d| d.bytes().all(|c| c.is_ascii_hexdigit()));
+            match digits.and_then(|d| u32::from_str_radix(d, 16).ok()) {
+                Some(code) => {
+                    self.pos += 4;
+                    Ok(code)
+                }
+                None => self.error("invalid unicode escape"),
+            }
+        }
+        fn string(&mut self) -> Result<String> {
+            self.pos += 1;
+            let mut out = String::new();
+            loop {
+                let Some(ch) = self.input[self.pos..].chars().next() else {
+                    return self.error("unterminated string");
+                };
+                self.pos += ch.len_utf8();
+                let escaped = match ch {
+                    '"' => return Ok(out),
+                    '\\' => {
+                        self.pos += 1;
+                        match self.input.as_bytes().get(self.pos - 1) {
+                            Some(b'"') => '"',
+                            Some(b'\\') => '\\',
+                            Some(b'/') => '/',
+                            Some(b'b') => '\u{8}',
+                            Some(b'f') => '\u{c}',
+                            Some(b'n') => '\n',
+                            Some(b'r') => '\r',
+                            Some(b't') => '\t',
+                            Some(b'u') => {
+                                let mut code = self.hex_escape()?;
+                                if (0xd800..0xdc00).contains(&code)
+                                    && self.input[self.pos..].starts_with("\\u")
+                                {
+                                    self.pos += 2;
+                                    let low = self.hex_escape()?;
+                                    if !(0xdc00..0xe000).contains(&low) {
+                                        return self.error("invalid unicode escape");
+                                    }
+                                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                }
+                                match char::from_u32(code) {
+                                    Some(ch) => ch,
+                                    None => return self.error("invalid unicode escape"),
+                                }
+                            }
+                            _ => return self.error("invalid escape"),
+                        }
+                    }
+                    ch if (ch as u32) < 0x20 => return self.error("control character in string"),
+                    ch => ch,
+                };
+                out.push(escaped);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Parse `input` as a property value, errors give their message and offset in `input`
+        fn value(input: &str) -> std::result::Result<Value<'static>, (&'static str, usize)> {
+            let wrapped = format!("{{\"v\":{input}}}");
+            match parse_object(&wrapped) {
+                Ok(mut properties) => Ok(properties.remove(0).1),
+                Err(e) => Err((e.message, e.offset.saturating_sub(5))),
+            }
+        }
+
+        #[test]
+        fn parses_values() {
+            assert_eq!(
+                value(r#" {"a": [1, -0.5e+3, "x"], "b": {}, "c": true, "d": null} "#),
+                Ok(Object::new()
+                    .with_property(
+                        "a",
+                        Value::Array(vec!["1".into(), "-0.5e+3".into(), "x".into()])
+                    )
+                    .with_property("b", Object::new())
+                    .with_property("c", "true")
+                    .with_property("d", "")
+                    .into())
+            );
+            assert_eq!(
+                parse_object(r#"{"b": "1", "a": "2"}"#).ok(),
+                Some(vec![
+                    ("b".to_owned(), "1".into()),
+                    ("a".to_owned(), "2".into())
+                ])
+            );
+        }
+
+        #[test]
+        fn parses_string_escapes() {
+            assert_eq!(
+                value(r#""\"\\\/\b\f\n\r\t\u00e9\ud83d\ude00""#),
+                Ok("\"\\/\u{8}\u{c}\n\r\té😀".into())
+            );
+            assert_eq!(value(r#""\u+123""#), Err(("invalid unicode escape", 3)));
+            assert_eq!(value(r#""\ud83d""#), Err(("invalid unicode escape", 7)));
+            assert_eq!(value(r#""\x""#), Err(("invalid escape", 3)));
+            assert_eq!(value("\"\n\""), Err(("control character in string", 2)));
+            assert_eq!(value(r#""abc"#), Err(("unterminated string", 5)));
+        }
+
+        #[test]
+        fn rejects_invalid_numbers() {
+            for number in ["01", "-01", "1.", "1e", "1e+", "-", "-a"] {
+                assert_eq!(value(number), Err(("invalid number", 0)), "{number}");
+            }
+            for number in ["0", "-0", "10", "1.25", "1E5", "2e-3"] {
+                assert_eq!(value(number), Ok(number.into()));
+            }
+            assert_eq!(value(".5"), Err(("expected a value", 0)));
+            assert_eq!(value("+1"), Err(("expected a value", 0)));
+        }
+
+        #[test]
+        fn rejects_invalid_structure() {
+            assert_eq!(parse_object("[]").err().map(|e| e.offset), Some(0));
+            assert_eq!(value("[1 2]"), Err(("expected ',' or ']'", 3)));
+            assert_eq!(value(r#"{"a" 1}"#), Err(("expected ':'", 5)));
+            assert_eq!(
+                value(r#"{"a.b": 1}"#),
+                Err(("property names may not contain '.'", 1))
+            );
+            assert_eq!(value("nul"), Err(("expected a value", 0)));
+            assert_eq!(value("1}x"), Err(("unexpected trailing characters", 2)));
+        }
+
+        #[test]
+        fn nesting_is_limited() {
+            let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+            assert!(value(&nested(MAX_DEPTH - 1)).is_ok());
+            assert_eq!(
+                value(&nested(MAX_DEPTH)),
+                Err(("arrays and objects are nested too deeply", MAX_DEPTH - 1))
+            );
+            assert_eq!(
+                value(&"[".repeat(100_000)),
+                Err(("arrays and objects are nested too deeply", MAX_DEPTH - 1))
+            );
+        }
+    }
+}
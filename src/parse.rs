@@ -1,4 +1,6 @@
 //! Parsing utilities for templates
+use std::borrow::Cow;
+
 use crate::Variable;
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -88,6 +90,15 @@ pub enum ErrorKind {
     },
     /// More than 1 variable in a template (`{{ ... }}`) block
     TooManyVariablesInBlock,
+    /// Block (e.g. `{{#if ...}}`) which is never closed, points at the opening tag
+    UnclosedBlock,
+    /// `{{else}}` or closing tag (e.g. `{{/if}}`) which does not match an open block
+    UnmatchedBlockTag,
+    /// Variable starting with `@` which is not one of the special variables (`@index`, `@key`,
+    /// `@first` and `@last`)
+    ReservedName,
+    /// Block opened inside [`MAX_BLOCK_DEPTH`] other blocks, points at its opening tag
+    BlocksNestedTooDeeply,
 }
 impl std::fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -101,9 +112,82 @@ impl std::fmt::Display for ErrorKind {
             ErrorKind::TooManyVariablesInBlock => {
                 f.write_str("more than 1 variable in template block")
             }
+            ErrorKind::UnclosedBlock => f.write_str("block is never closed"),
+            ErrorKind::UnmatchedBlockTag => f.write_str("block tag does not match any open block"),
+            ErrorKind::ReservedName => f.write_str("names starting with '@' are reserved"),
+            ErrorKind::BlocksNestedTooDeeply => f.write_fmt(format_args!(
+                "more than {MAX_BLOCK_DEPTH} blocks nested inside each other"
+            )),
         }
     }
 }
+/// How many blocks (e.g. `{{#if ...}}`) can be nested inside each other
+///
+/// This is per template, when rendering blocks inside partials count towards the
+/// [depth limit](crate::Context::set_depth_limit) too
+pub const MAX_BLOCK_DEPTH: usize = 64;
+/// Strings which mark the start and end of a tag
+///
+/// Defaults to `{{` and `}}`. The rest of the syntax stays the same with other delimiters,
+/// e.g. with `<%` and `%>` a raw variable is `<%{ var }%>` and a block is `<%#if var%>`.
+/// Delimiters should not start with characters which can appear in variable names
+///
+/// ```
+/// # use handybars::{Variable, parse::*};
+/// let delimiters = Delimiters::new("<%", "%>");
+/// assert_eq!(
+///     tokenize_with(r"\frac{{a}}{<% b %>}", delimiters),
+///     Ok(vec![
+///         Token::Str(r"\frac{{a}}{"),
+///         Token::Variable(Variable::single("b")),
+///         Token::Str("}"),
+///     ])
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Delimiters {
+    open: Cow<'static, str>,
+    close: Cow<'static, str>,
+}
+impl Delimiters {
+    /// Construct new delimiters
+    ///
+    /// Panics: if either delimiter is empty or if they are the same
+    ///
+    /// ```should_panic
+    /// # use handybars::parse::Delimiters;
+    /// Delimiters::new("%%", "%%"); // boom
+    /// ```
+    pub fn new(open: impl Into<String>, close: impl Into<String>) -> Self {
+        let (open, close) = (open.into(), close.into());
+        assert!(
+            !open.is_empty() && !close.is_empty(),
+            "delimiters may not be empty"
+        );
+        assert_ne!(open, close, "opening and closing delimiters must differ");
+        Self {
+            open: Cow::Owned(open),
+            close: Cow::Owned(close),
+        }
+    }
+    /// String which starts a tag
+    pub fn open(&self) -> &str {
+        &self.open
+    }
+    /// String which ends a tag
+    pub fn close(&self) -> &str {
+        &self.close
+    }
+}
+impl Default for Delimiters {
+    fn default() -> Self {
+        Self {
+            open: Cow::Borrowed("{{"),
+            close: Cow::Borrowed("}}"),
+        }
+    }
+}
+
 impl std::error::Error for Error {}
 
 /// Type for errors reported by parsing
@@ -113,6 +197,8 @@ pub struct Error {
     ///
     /// First is column, second is row
     offset: Location,
+    /// Offset into source for error in bytes
+    byte_offset: usize,
     /// Type of error
     ty: ErrorKind,
 }
@@ -129,18 +215,23 @@ impl std::fmt::Display for Error {
 }
 
 impl Error {
-    /// Construct a new error with offset and kind
-    pub fn new(offset: impl Into<Location>, ty: ErrorKind) -> Self {
+    /// Construct a new error with offset, byte offset and kind
+    pub fn new(offset: impl Into<Location>, byte_offset: usize, ty: ErrorKind) -> Self {
         Self {
             offset: offset.into(),
+            byte_offset,
             ty,
         }
     }
     /// Add offset to existing error
-    pub fn add_offset(mut self, offset: impl Into<Location>) -> Self {
+    pub fn add_offset(mut self, offset: impl Into<Location>, byte_offset: usize) -> Self {
         self.offset += offset.into();
+        self.byte_offset += byte_offset;
         self
     }
+    fn at(pos: Position, ty: ErrorKind) -> Self {
+        Self::new(pos.location, pos.byte, ty)
+    }
 
     /// What kind of error is this
     pub fn kind(&self) -> &ErrorKind {
@@ -151,6 +242,13 @@ impl Error {
     pub fn location(&self) -> Location {
         self.offset
     }
+
+    /// Offset in bytes from the start of the input that this error ocurred
+    ///
+    /// Unlike [`location`](Error::location) this can be used to index directly into the input
+    pub fn byte_offset(&self) -> usize {
+        self.byte_offset
+    }
 }
 pub(crate) fn is_valid_identifier_ch(ch: u8) -> bool {
     !(ch.is_ascii_whitespace()
@@ -189,6 +287,7 @@ pub(crate) fn try_parse_variable_segment(input: &[u8]) -> Result<&[u8]> {
     if input.is_empty() {
         return Err(Error::new(
             Location::zero(),
+            0,
             ErrorKind::EmptyVariableSegment,
         ));
     }
@@ -200,10 +299,10 @@ pub(crate) fn try_parse_variable_segment(input: &[u8]) -> Result<&[u8]> {
             col: offset,
         };
         match ch as char {
-            '\n' => return Err(Error::new(pos, ErrorKind::NewlineInVariableSegment)),
+            '\n' => return Err(Error::new(pos, offset, ErrorKind::NewlineInVariableSegment)),
             _ if !is_valid_identifier_ch(ch) => {
                 return if offset == 0 {
-                    Err(Error::new(pos, ErrorKind::EmptyVariableSegment))
+                    Err(Error::new(pos, offset, ErrorKind::EmptyVariableSegment))
                 } else {
                     Ok(&input[..offset])
                 };
@@ -215,30 +314,237 @@ pub(crate) fn try_parse_variable_segment(input: &[u8]) -> Result<&[u8]> {
     Ok(input)
 }
 
-fn parse_template_inner(input: &[u8]) -> Option<Result<(Variable, usize)>> {
-    let mut head = 0;
-    while head < input.len() && input[head] as char == ' ' {
+/// Parse a variable tag, `input` should start after the opening delimiter
+///
+/// If `raw` the closing delimiter must be preceded by a `}`. Returns the variable and the
+/// length up to and including the closing delimiter
+fn parse_template_inner<'i>(
+    input: &'i [u8],
+    close: &[u8],
+    raw: bool,
+) -> Option<Result<(Variable<'i>, usize)>> {
+    let (var, head) = match parse_tag_variable(input)? {
+        Ok(v) => v,
+        Err(e) => return Some(Err(e)),
+    };
+    find_tag_end(input, head, close, raw).map(|len| Ok((var, len)))
+}
+
+/// Parse the variable at the start of a tag, after any spaces
+///
+/// Returns the variable and the length up to the end of it
+fn parse_tag_variable<'i>(input: &'i [u8]) -> Option<Result<(Variable<'i>, usize)>> {
+    let head = input.iter().take_while(|c| **c as char == ' ').count();
+    Some(
+        match super::parse_with_terminator(str_from_utf8(&input[head..]), false) {
+            Ok(var) => {
+                let len = head + var.len();
+                Ok((var, len))
+            }
+            Err(Error {
+                ty: ErrorKind::EmptyVariableSegment,
+                byte_offset: 0,
+                ..
+            }) => return None,
+            Err(e) => Err(e.add_offset(Location::new(head, 0), head)),
+        },
+    )
+}
+
+/// Length of a tag up to and including the closing delimiter, which is searched for from `head`
+///
+/// If `raw` the closing delimiter must be preceded by a `}`
+fn find_tag_end(input: &[u8], mut head: usize, close: &[u8], raw: bool) -> Option<usize> {
+    while head < input.len() {
+        let rest = &input[head..];
+        if raw && rest.starts_with(b"}") && rest[1..].starts_with(close) {
+            return Some(head + 1 + close.len());
+        }
+        if rest.starts_with(close) {
+            return (!raw).then_some(head + close.len());
+        }
         head += 1;
     }
-    let var = match super::parse_with_terminator(str_from_utf8(&input[head..]), false) {
+    None
+}
+
+/// Parse the condition of an `if` block, starting after the keyword
+///
+/// This is a variable optionally followed by a [`Comparison`], after which only spaces may come
+/// before the closing delimiter. Returns the length up to and including the closing delimiter
+fn parse_condition_inner<'i>(
+    input: &'i [u8],
+    close: &[u8],
+) -> Option<Result<(Variable<'i>, Option<Comparison<'i>>, usize)>> {
+    let (var, mut head) = match parse_tag_variable(input)? {
         Ok(v) => v,
-        Err(Error {
-            ty: ErrorKind::EmptyVariableSegment,
-            offset: Location { line: 0, col: 0 },
-        }) => return None,
-        Err(e) => return Some(Err(e.add_offset(Location::new(head, 0)))),
+        Err(e) => return Some(Err(e)),
+    };
+    let spaces = |head: usize| {
+        input[head..]
+            .iter()
+            .take_while(|c| **c as char == ' ')
+            .count()
+    };
+    head += spaces(head);
+    let Some(op) = CompareOp::ALL
+        .into_iter()
+        .find(|op| input[head..].starts_with(op.symbol().as_bytes()))
+    else {
+        return find_tag_end(input, head, close, false).map(|len| Ok((var, None, len)));
+    };
+    head += op.symbol().len();
+    head += spaces(head);
+    let rhs_len = (head..input.len())
+        .take_while(|i| input[*i] as char != ' ' && !input[*i..].starts_with(close))
+        .count();
+    let rhs = str_from_utf8(&input[head..head + rhs_len]);
+    let rhs = if parse_number(rhs).is_some() {
+        Operand::Number(rhs)
+    } else {
+        match super::parse_with_terminator(rhs, true) {
+            Ok(var) => Operand::Variable(var),
+            Err(e) => return Some(Err(e.add_offset(Location::new(head, 0), head))),
+        }
     };
-    head += var.len();
-    fn check_end_condition(head: usize, input: &[u8]) -> bool {
-        input[head] as char == '}' && input[head + 1] as char == '}'
+    let end = head + rhs_len;
+    let end = end + spaces(end);
+    Some(match find_tag_end(input, end, close, false)? {
+        len if len == end + close.len() => Ok((var, Some(Comparison { op, rhs }), len)),
+        _ => Err(Error::new(
+            Location::new(end, 0),
+            end,
+            ErrorKind::TooManyVariablesInBlock,
+        )),
+    })
+}
+
+/// Parse a number in a comparison, only finite numbers are accepted
+pub(crate) fn parse_number(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+/// Parse a `json var` tag, `input` should start after the opening delimiter
+///
+/// Returns `None` if this is not a `json` tag, including when `json` is the variable itself
+fn parse_json_inner<'i>(input: &'i [u8], close: &[u8]) -> Option<Result<(Variable<'i>, usize)>> {
+    let start = input.iter().take_while(|c| **c as char == ' ').count();
+    if !input[start..].starts_with(b"json ") {
+        return None;
     }
-    while head < input.len() - 1 {
-        if check_end_condition(head, input) {
-            return Some(Ok((var, head + 2)));
+    let offset = start + "json".len();
+    Some(
+        match parse_template_inner(&input[offset..], close, false)? {
+            Ok((var, len)) => Ok((var, offset + len)),
+            Err(e) => Err(e.add_offset((offset, 0), offset)),
+        },
+    )
+}
+
+/// Parse a comment, `input` should start at the `!` after the opening delimiter
+///
+/// Comments opened with `{{!--` run until the next `--}}` and so may contain `}}`,
+/// all others end at the first `}}`. Returns the comment text and the length up to and
+/// including the closing delimiter, or `None` if the comment is never closed.
+fn parse_comment_inner<'i>(input: &'i [u8], close: &[u8]) -> Option<(&'i str, usize)> {
+    let (start, dashes) = if input.starts_with(b"!--") {
+        (3, b"--".as_slice())
+    } else {
+        (1, b"".as_slice())
+    };
+    let end = (start..input.len()).find(|&i| {
+        input[i..].starts_with(dashes) && input[i + dashes.len()..].starts_with(close)
+    })?;
+    Some((
+        str_from_utf8(&input[start..end]),
+        end + dashes.len() + close.len(),
+    ))
+}
+
+/// Parse a tag consisting of only `keyword` and optional spaces, e.g. `{{ else }}`
+///
+/// Returns the length up to and including the closing delimiter
+fn parse_keyword_inner(input: &[u8], keyword: &str, close: &[u8]) -> Option<usize> {
+    let spaces = |from: usize| {
+        input[from.min(input.len())..]
+            .iter()
+            .take_while(|c| **c as char == ' ')
+            .count()
+    };
+    let start = spaces(0);
+    if !input[start..].starts_with(keyword.as_bytes()) {
+        return None;
+    }
+    let end = start + keyword.len();
+    let end = end + spaces(end);
+    input[end..].starts_with(close).then_some(end + close.len())
+}
+
+/// Kind, variable and comparison of an opening block tag, followed by its length
+type BlockOpen<'i> = (BlockKind, Variable<'i>, Option<Comparison<'i>>, usize);
+
+/// Parse an opening block tag, `input` should start at the `#` after the opening delimiter
+///
+/// Returns `None` if this is not a known block
+fn parse_block_open_inner<'i>(input: &'i [u8], close: &[u8]) -> Option<Result<BlockOpen<'i>>> {
+    let kind = [BlockKind::If, BlockKind::Each].into_iter().find(|k| {
+        input[1..].starts_with(k.name().as_bytes())
+            && input.get(k.name().len() + 1).map(|c| *c as char) == Some(' ')
+    })?;
+    let offset = kind.name().len() + 1;
+    let parsed = match kind {
+        BlockKind::If => parse_condition_inner(&input[offset..], close),
+        BlockKind::Each => parse_template_inner(&input[offset..], close, false)
+            .map(|r| r.map(|(var, len)| (var, None, len))),
+    };
+    Some(match parsed {
+        Some(Ok((var, comparison, len))) => Ok((kind, var, comparison, len + offset)),
+        Some(Err(e)) => Err(e.add_offset((offset, 0), offset)),
+        None => {
+            let spaces = input[offset..]
+                .iter()
+                .take_while(|c| **c as char == ' ')
+                .count();
+            Err(Error::new(
+                (offset + spaces, 0),
+                offset + spaces,
+                ErrorKind::EmptyVariableSegment,
+            ))
         }
-        head += 1;
+    })
+}
+
+/// Parse a partial include, `input` should start at the `>` after the opening delimiter
+///
+/// Names are made up of identifier characters, `.` and `/`. Returns the name and the
+/// length up to and including the closing delimiter
+fn parse_partial_inner<'i>(input: &'i [u8], close: &[u8]) -> Option<(&'i str, usize)> {
+    let start = 1 + input[1..].iter().take_while(|c| **c as char == ' ').count();
+    let name_len = input[start..]
+        .iter()
+        .take_while(|c| is_valid_identifier_ch(**c) || matches!(**c as char, '.' | '/'))
+        .count();
+    if name_len == 0 {
+        return None;
     }
-    None
+    let name = str_from_utf8(&input[start..start + name_len]);
+    let len = parse_keyword_inner(&input[start..], name, close)?;
+    Some((name, start + len))
+}
+
+/// Parse a closing tag, `input` should start at the `/` after the opening delimiter
+///
+/// Returns the name of the block being closed and the length up to and including the closing delimiter
+fn parse_block_close_inner<'i>(input: &'i [u8], close: &[u8]) -> Option<(&'i str, usize)> {
+    let name_len = input[1..]
+        .iter()
+        .take_while(|c| is_valid_identifier_ch(**c))
+        .count();
+    if name_len == 0 {
+        return None;
+    }
+    let len = parse_keyword_inner(&input[1..], str_from_utf8(&input[1..name_len + 1]), close)?;
+    Some((str_from_utf8(&input[1..name_len + 1]), len + 1))
 }
 
 #[inline]
@@ -261,6 +567,8 @@ pub(crate) fn str_from_utf8(chars: &[u8]) -> &str {
 /// This exists to allow true zero-allocation tokenization. See [`tokenize`](crate::parse::tokenize) for
 /// a version of this which gives you a vector and result.
 ///
+/// Input is scanned lazily, each call to `next` only reads as far as the end of the token it returns.
+///
 /// ```
 /// # use handybars::{*, parse::*};
 /// let mut tokens = Tokenize::new("some {{ text }}");
@@ -285,7 +593,11 @@ pub struct Tokenize<'a> {
     row: usize,
     col: usize,
     hit_error: bool,
-    var_next: Option<Variable<'a>>,
+    token_next: Option<Result<Tag<'a>>>,
+    /// Where each variable was found, in source order, if asked for. See [`variable_count`]
+    variable_locations: Option<Vec<Location>>,
+    delimiters: Delimiters,
+    escape: Option<char>,
 }
 
 impl<'a> Tokenize<'a> {
@@ -298,95 +610,477 @@ impl<'a> Tokenize<'a> {
             row: 0,
             col: 0,
             hit_error: false,
-            var_next: None,
+            token_next: None,
+            variable_locations: None,
+            delimiters: Delimiters::default(),
+            escape: Some('\\'),
         }
     }
+    /// Use `delimiters` for tags instead of `{{` and `}}`
+    ///
+    /// ```
+    /// # use handybars::{*, parse::*};
+    /// let mut tokens = Tokenize::new("{{ a }}<% b %>").with_delimiters(Delimiters::new("<%", "%>"));
+    /// assert_eq!(tokens.next(), Some(Ok(Token::Str("{{ a }}"))));
+    /// assert_eq!(tokens.next(), Some(Ok(Token::Variable(Variable::single("b")))));
+    /// ```
+    pub fn with_delimiters(mut self, delimiters: Delimiters) -> Self {
+        self.delimiters = delimiters;
+        self
+    }
+    /// Character which escapes an opening delimiter, or itself, so it is kept as text
+    ///
+    /// Defaults to `\`, with `None` there is no escaping and an opening delimiter always
+    /// starts a tag
+    ///
+    /// ```
+    /// # use handybars::{*, parse::*};
+    /// let mut tokens = Tokenize::new(r"C:\{{ dir }}").with_escape_char(Some('`'));
+    /// assert_eq!(tokens.next(), Some(Ok(Token::Str(r"C:\"))));
+    /// assert_eq!(tokens.next(), Some(Ok(Token::Variable(Variable::single("dir")))));
+    /// ```
+    pub fn with_escape_char(mut self, escape: Option<char>) -> Self {
+        self.escape = escape;
+        self
+    }
+    /// Record the location of each variable, see [`Tokenize::variable_locations`]
+    pub(crate) fn with_variable_locations(mut self) -> Self {
+        self.variable_locations = Some(Vec::new());
+        self
+    }
+    /// Locations of every variable seen so far, in the order they appear in the source
+    ///
+    /// This includes block conditions and `each` targets, a full token uses
+    /// [`variable_count`] of these. Locations are only recorded after
+    /// [`Tokenize::with_variable_locations`] and the ones which are no longer needed can be
+    /// dropped with [`Tokenize::forget_variable_locations`]
+    pub(crate) fn variable_locations(&self) -> &[Location] {
+        self.variable_locations.as_deref().unwrap_or_default()
+    }
+    /// Drop the first `count` entries of [`Tokenize::variable_locations`]
+    ///
+    /// Panics: If fewer than `count` locations have been recorded
+    pub(crate) fn forget_variable_locations(&mut self, count: usize) {
+        if let Some(locations) = &mut self.variable_locations {
+            locations.drain(..count);
+        }
+    }
+    /// Location of the byte at `offset`, which must not be before `head`
+    fn location_of(&self, offset: usize) -> Location {
+        let mut location = Location::new(self.col, self.row);
+        for ch in &self.chars[self.head..offset] {
+            if *ch as char == '\n' {
+                location.col = 0;
+                location.line += 1;
+            } else {
+                location.col += 1;
+            }
+        }
+        location
+    }
+    /// Move `head` forward by `len` bytes, keeping `row` and `col` in step
+    fn advance(&mut self, len: usize) {
+        let location = self.location_of(self.head + len);
+        self.col = location.col;
+        self.row = location.line;
+        self.head += len;
+    }
+    /// Move an error from inside the tag at `head` to its place in the whole input
+    ///
+    /// `skip` is how far into the tag the input given to the inner parser started. Inner
+    /// parsers only see part of a tag and so cannot know which line they are on, the byte
+    /// offset is used to work it out instead
+    fn locate_error(&self, e: Error, skip: usize) -> Error {
+        let byte_offset = self.head + skip + e.byte_offset;
+        Error::new(self.location_of(byte_offset), byte_offset, e.ty)
+    }
 }
 
-impl<'a> Iterator for Tokenize<'a> {
-    type Item = Result<Token<'a>>;
+/// Tags as seen by the scanner, before blocks are matched up
+enum Tag<'a> {
+    Token(Token<'a>),
+    Open(BlockKind, Variable<'a>, Option<Comparison<'a>>, Position),
+    Else(Position),
+    Close(&'a str, Position),
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next) = self.var_next.take() {
-            return Some(Ok(Token::Variable(next)));
+/// Where a tag starts, kept so errors about it can be reported later
+#[derive(Clone, Copy)]
+struct Position {
+    location: Location,
+    byte: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BlockKind {
+    If,
+    Each,
+}
+impl BlockKind {
+    fn name(self) -> &'static str {
+        match self {
+            BlockKind::If => "if",
+            BlockKind::Each => "each",
+        }
+    }
+}
+
+impl<'a> Tokenize<'a> {
+    fn next_tag(&mut self) -> Option<Result<Tag<'a>>> {
+        if let Some(next) = self.token_next.take() {
+            return Some(next);
         }
         if self.hit_error {
             return None;
         } else if self.head >= self.chars.len() {
-            if self.tail < self.chars.len() - 1 {
-                let val = Some(Ok(Token::Str(str_from_utf8(&self.chars[self.tail..]))));
-                self.tail = self.chars.len() - 1;
+            if self.tail < self.chars.len() {
+                let val = Some(Ok(Tag::Token(Token::Str(str_from_utf8(
+                    &self.chars[self.tail..],
+                )))));
+                self.tail = self.chars.len();
                 return val;
             }
             return None;
         }
 
+        let open = self.delimiters.open.len();
+        let mut escape_buf = [0; 4];
+        let escape = self
+            .escape
+            .map(|ch| &*ch.encode_utf8(&mut escape_buf))
+            .unwrap_or_default()
+            .as_bytes();
         while self.head < self.chars.len() {
+            if !escape.is_empty() && self.chars[self.head..].starts_with(escape) {
+                let rest = &self.chars[self.head + escape.len()..];
+                let escaped = if rest.starts_with(self.delimiters.open.as_bytes()) {
+                    open
+                } else if rest.starts_with(escape) {
+                    escape.len()
+                } else {
+                    0
+                };
+                if escaped != 0 {
+                    // drop the escape, what it escapes becomes the start of the next string
+                    let prev = &self.chars[self.tail..self.head];
+                    self.advance(escape.len());
+                    self.tail = self.head;
+                    self.advance(escaped);
+                    if !prev.is_empty() {
+                        return Some(Ok(Tag::Token(Token::Str(str_from_utf8(prev)))));
+                    }
+                    continue;
+                }
+            }
             let pos = (self.col, self.row);
-            let var = if self.chars[self.head] as char == '{'
-                && self.chars[self.head + 1] as char == '{'
-            {
-                parse_template_inner(&self.chars[self.head + 2..])
-                    .transpose()
-                    .map_err(|e| e.add_offset((pos.0 + 2, pos.1)))
-                    .transpose()
+            // a partial delimiter (including one at the very end of the input) is left as text
+            let tag = if self.chars[self.head..].starts_with(self.delimiters.open.as_bytes()) {
+                let inner = &self.chars[self.head + open..];
+                let close = self.delimiters.close.as_bytes();
+                let location = Position {
+                    location: Location::new(pos.0, pos.1),
+                    byte: self.head,
+                };
+                match inner.first().map(|c| *c as char) {
+                    Some('!') => parse_comment_inner(inner, close)
+                        .map(|(c, len)| Ok((Tag::Token(Token::Comment(c)), len))),
+                    Some('#') => parse_block_open_inner(inner, close).map(|r| {
+                        r.map(|(kind, var, cmp, len)| (Tag::Open(kind, var, cmp, location), len))
+                            .map_err(|e| self.locate_error(e, open))
+                    }),
+                    Some('/') => parse_block_close_inner(inner, close)
+                        .map(|(name, len)| Ok((Tag::Close(name, location), len))),
+                    Some('>') => parse_partial_inner(inner, close)
+                        .map(|(name, len)| Ok((Tag::Token(Token::Partial(name)), len))),
+                    Some('{') => parse_template_inner(&inner[1..], close, true).map(|r| {
+                        r.map(|(var, len)| (Tag::Token(Token::RawVariable(var)), len + 1))
+                            .map_err(|e| self.locate_error(e, open + 1))
+                    }),
+                    _ => match parse_keyword_inner(inner, "else", close) {
+                        Some(len) => Some(Ok((Tag::Else(location), len))),
+                        None => parse_json_inner(inner, close)
+                            .map(|r| r.map(|(var, len)| (Token::JsonVariable(var), len)))
+                            .or_else(|| {
+                                parse_template_inner(inner, close, false)
+                                    .map(|r| r.map(|(var, len)| (Token::Variable(var), len)))
+                            })
+                            .map(|r| {
+                                r.map(|(token, len)| (Tag::Token(token), len))
+                                    .map_err(|e| self.locate_error(e, open))
+                            }),
+                    },
+                }
             } else {
                 None
             };
-            match var {
-                Some(Ok((var, len))) => {
+            match tag {
+                Some(Ok((tag, len))) => {
+                    if let Some(locations) = &mut self.variable_locations {
+                        if matches!(
+                            tag,
+                            Tag::Open(..)
+                                | Tag::Token(
+                                    Token::Variable(_)
+                                        | Token::RawVariable(_)
+                                        | Token::JsonVariable(_)
+                                )
+                        ) {
+                            locations.push(Location::new(pos.0, pos.1));
+                        }
+                    }
                     let prev_tail = self.tail;
                     let prev_head = self.head;
                     let should_add_prev = self.tail != self.head;
-                    self.head += len + 2;
+                    self.advance(len + open);
                     self.tail = self.head;
-                    self.col += len + 2;
                     if should_add_prev {
-                        self.var_next.replace(var);
-                        let val = Some(Ok(Token::Str(str_from_utf8(
+                        self.token_next.replace(Ok(tag));
+                        let val = Some(Ok(Tag::Token(Token::Str(str_from_utf8(
                             &self.chars[prev_tail..prev_head],
-                        ))));
+                        )))));
                         return val;
                     } else {
-                        return Some(Ok(Token::Variable(var)));
+                        return Some(Ok(tag));
                     }
                 }
                 Some(Err(e)) => {
                     self.hit_error = true;
-                    return Some(Err(e));
-                }
-                None => {
-                    if self.chars[self.head] as char == '\n' {
-                        self.col = 0;
-                        self.row += 1;
-                    } else {
-                        self.col += 1;
+                    if self.tail != self.head {
+                        self.token_next.replace(Err(e));
+                        return Some(Ok(Tag::Token(Token::Str(str_from_utf8(
+                            &self.chars[self.tail..self.head],
+                        )))));
                     }
-                    self.head += 1;
+                    return Some(Err(e));
                 }
+                None => self.advance(1),
             }
         }
-        if self.tail != self.chars.len() - 1 {
-            let val = Some(Ok(Token::Str(str_from_utf8(&self.chars[self.tail..]))));
-            self.tail = self.chars.len() - 1;
+        if self.tail < self.chars.len() {
+            let val = Some(Ok(Tag::Token(Token::Str(str_from_utf8(
+                &self.chars[self.tail..],
+            )))));
+            self.tail = self.chars.len();
             return val;
         }
         None
     }
+
+    /// Collect the body of a block opened at `start` up to its closing tag
+    ///
+    /// `depth` is the number of blocks this one is inside of
+    fn parse_block(
+        &mut self,
+        kind: BlockKind,
+        var: Variable<'a>,
+        comparison: Option<Comparison<'a>>,
+        start: Position,
+        depth: usize,
+    ) -> Result<Token<'a>> {
+        if depth >= MAX_BLOCK_DEPTH {
+            return Err(Error::at(start, ErrorKind::BlocksNestedTooDeeply));
+        }
+        let mut body = Vec::new();
+        let mut otherwise = None;
+        loop {
+            let tag = self
+                .next_tag()
+                .unwrap_or_else(|| Err(Error::at(start, ErrorKind::UnclosedBlock)))?;
+            let token = match tag {
+                Tag::Token(t) => t,
+                Tag::Open(kind, var, cmp, start) => {
+                    self.parse_block(kind, var, cmp, start, depth + 1)?
+                }
+                Tag::Else(_) if kind == BlockKind::If && otherwise.is_none() => {
+                    otherwise = Some(Vec::new());
+                    continue;
+                }
+                Tag::Close(name, _) if name == kind.name() => break,
+                Tag::Else(location) | Tag::Close(_, location) => {
+                    return Err(Error::at(location, ErrorKind::UnmatchedBlockTag))
+                }
+            };
+            otherwise.as_mut().unwrap_or(&mut body).push(token);
+        }
+        Ok(match kind {
+            BlockKind::If => Token::Block {
+                condition: var,
+                comparison,
+                then: body,
+                otherwise: otherwise.unwrap_or_default(),
+            },
+            BlockKind::Each => Token::Each { var, body },
+        })
+    }
+}
+
+impl<'a> Iterator for Tokenize<'a> {
+    type Item = Result<Token<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = match self.next_tag()? {
+            Ok(Tag::Token(t)) => Ok(t),
+            Ok(Tag::Open(kind, var, cmp, start)) => self.parse_block(kind, var, cmp, start, 0),
+            Ok(Tag::Else(location) | Tag::Close(_, location)) => {
+                Err(Error::at(location, ErrorKind::UnmatchedBlockTag))
+            }
+            Err(e) => Err(e),
+        };
+        if token.is_err() {
+            self.hit_error = true;
+            self.token_next = None;
+        }
+        Some(token)
+    }
+}
+
+impl std::iter::FusedIterator for Tokenize<'_> {}
+
+/// Number of variables in `token`, including those in any nested tokens
+///
+/// Variables are counted in source order, i.e. a block's own variable comes before the
+/// variables in its body, the same order [`Tokenize::variable_locations`] records them in
+pub(crate) fn variable_count(token: &Token<'_>) -> usize {
+    match token {
+        Token::Variable(_) | Token::RawVariable(_) | Token::JsonVariable(_) => 1,
+        Token::Block {
+            then, otherwise, ..
+        } => {
+            1 + then
+                .iter()
+                .chain(otherwise)
+                .map(variable_count)
+                .sum::<usize>()
+        }
+        Token::Each { body, .. } => 1 + body.iter().map(variable_count).sum::<usize>(),
+        Token::Str(_) | Token::Partial(_) | Token::Comment(_) => 0,
+    }
 }
 
 /// Tokenize an input with allocation
-pub fn tokenize(input: &str) -> Result<Vec<Token>> {
+pub fn tokenize(input: &str) -> Result<Vec<Token<'_>>> {
     Tokenize::new(input).collect()
 }
 
+/// Tokenize an input with allocation using custom delimiters
+pub fn tokenize_with(input: &str, delimiters: Delimiters) -> Result<Vec<Token<'_>>> {
+    Tokenize::new(input).with_delimiters(delimiters).collect()
+}
+
+/// Comparison in the condition of an `{{#if}}` block, e.g. the `> 10` of `{{#if count > 10}}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison<'a> {
+    #[allow(missing_docs)]
+    pub op: CompareOp,
+    /// What the condition variable is compared to
+    pub rhs: Operand<'a>,
+}
+
+/// Operator used in a [`Comparison`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CompareOp {
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+}
+impl CompareOp {
+    /// Every operator, those which are a prefix of another come last
+    const ALL: [CompareOp; 6] = [
+        CompareOp::Eq,
+        CompareOp::Ne,
+        CompareOp::Le,
+        CompareOp::Ge,
+        CompareOp::Lt,
+        CompareOp::Gt,
+    ];
+    /// How the operator is written in a template
+    pub fn symbol(self) -> &'static str {
+        match self {
+            CompareOp::Eq => "==",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        }
+    }
+    /// Whether the operator holds for two values which compare as `ordering`
+    pub fn holds(self, ordering: std::cmp::Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering.is_eq(),
+            CompareOp::Ne => ordering.is_ne(),
+            CompareOp::Lt => ordering.is_lt(),
+            CompareOp::Le => ordering.is_le(),
+            CompareOp::Gt => ordering.is_gt(),
+            CompareOp::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+/// Right hand side of a [`Comparison`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operand<'a> {
+    /// Number literal, as written in the template. Anything which parses as a finite `f64` is
+    /// a number rather than a variable, a literal which doesn't is never comparable
+    Number(&'a str),
+    /// Value of another variable
+    Variable(Variable<'a>),
+}
+
 /// Type for tokens emitted by the parser
 #[derive(Debug, PartialEq, Eq)]
 pub enum Token<'a> {
     /// Variable for later expansion
     Variable(Variable<'a>),
+    /// Variable for later expansion which should never be escaped, `{{{ var }}}`
+    RawVariable(Variable<'a>),
+    /// Variable which is always escaped for use in a JSON string (`{{json var}}`)
+    JsonVariable(Variable<'a>),
     /// Untemplated string input
     Str(&'a str),
+    /// Conditional block, `{{#if condition}} then {{else}} otherwise {{/if}}`
+    ///
+    /// `otherwise` is empty if there is no `{{else}}`
+    ///
+    /// With a comparison the block is rendered if it holds, otherwise if `condition` is
+    /// truthy
+    Block {
+        #[allow(missing_docs)]
+        condition: Variable<'a>,
+        /// Comparison `condition` is checked against, `{{#if condition > 10}}`
+        comparison: Option<Comparison<'a>>,
+        #[allow(missing_docs)]
+        then: Vec<Token<'a>>,
+        #[allow(missing_docs)]
+        otherwise: Vec<Token<'a>>,
+    },
+    /// Loop over an array or object, `{{#each var}} body {{/each}}`
+    ///
+    /// Inside `body`, `this` refers to the current element and `@index` to its position.
+    /// When looping over an object `@key` is the name of the current property
+    Each {
+        #[allow(missing_docs)]
+        var: Variable<'a>,
+        #[allow(missing_docs)]
+        body: Vec<Token<'a>>,
+    },
+    /// Include a partial template by name, `{{> name }}`
+    Partial(&'a str),
+    /// Comment (`{{! ... }}` or `{{!-- ... --}}`), produces no output when rendered
+    ///
+    /// Holds the text of the comment without the surrounding markers
+    Comment(&'a str),
 }
 
 #[cfg(test)]
@@ -397,8 +1091,8 @@ mod tests {
 
     #[test]
     fn parse_template_inner_errors_with_space_in_path() {
-        let r = parse_template_inner("x .y}}".as_bytes()).unwrap();
-        assert_eq!(r, Err(Error::new((1, 0), ErrorKind::SpaceInPath)));
+        let r = parse_template_inner("x .y}}".as_bytes(), b"}}", false).unwrap();
+        assert_eq!(r, Err(Error::new((1, 0), 1, ErrorKind::SpaceInPath)));
     }
 
     #[test]
@@ -418,8 +1112,30 @@ mod tests {
         let tokens = tokenize("{{invalid. }} some text");
         assert_eq!(
             tokens,
-            Err(Error::new((9, 0), ErrorKind::EmptyVariableSegment))
+            Err(Error::new((9, 0), 9, ErrorKind::EmptyVariableSegment))
+        );
+    }
+
+    #[test]
+    fn tokenize_yields_tokens_before_reaching_an_error() {
+        let mut tokens = Tokenize::new("a{{ b }}c{{ d. }}");
+        assert_eq!(tokens.next(), Some(Ok(Token::Str("a"))));
+        assert_eq!(
+            tokens.next(),
+            Some(Ok(Token::Variable(Variable::single("b"))))
         );
+        assert_eq!(tokens.next(), Some(Ok(Token::Str("c"))));
+        assert!(matches!(tokens.next(), Some(Err(_))));
+        assert_eq!(tokens.next(), None);
+        assert_eq!(tokens.next(), None);
+    }
+
+    #[test]
+    fn tokenize_keeps_returning_none_once_finished() {
+        let mut tokens = Tokenize::new("a{{ b }}");
+        assert_eq!(tokens.by_ref().count(), 2);
+        assert_eq!(tokens.next(), None);
+        assert_eq!(Tokenize::new("").next(), None);
     }
 
     #[test]
@@ -428,6 +1144,483 @@ mod tests {
         assert_eq!(tokens, Ok(vec![Token::Variable(Variable::single("test"))]));
     }
 
+    #[test]
+    fn comments_are_tokenized_separately_from_adjacent_text() {
+        let tokens = tokenize("before{{! a note }}after");
+        assert_eq!(
+            tokens,
+            Ok(vec![
+                Token::Str("before"),
+                Token::Comment(" a note "),
+                Token::Str("after")
+            ])
+        );
+    }
+
+    #[test]
+    fn back_to_back_comments_are_separate_tokens() {
+        let tokens = tokenize("{{!one}}{{!-- two --}}{{ x }}");
+        assert_eq!(
+            tokens,
+            Ok(vec![
+                Token::Comment("one"),
+                Token::Comment(" two "),
+                Token::Variable(Variable::single("x"))
+            ])
+        );
+    }
+
+    #[test]
+    fn block_comments_may_contain_closing_braces() {
+        let tokens = tokenize("{{!-- {{ x }} --}}text");
+        assert_eq!(
+            tokens,
+            Ok(vec![Token::Comment(" {{ x }} "), Token::Str("text")])
+        );
+    }
+
+    #[test]
+    fn multiline_comments_keep_location_tracking_in_sync() {
+        let tokens = tokenize("{{! a\nb }}\n{{ x. }}");
+        assert_eq!(
+            tokens,
+            Err(Error::new((4, 2), 15, ErrorKind::EmptyVariableSegment))
+        );
+    }
+
+    #[test]
+    fn escaped_braces_are_literal_text() {
+        let tokens = tokenize(r"echo \{{ x }} {{ y }}");
+        assert_eq!(
+            tokens,
+            Ok(vec![
+                Token::Str("echo "),
+                Token::Str("{{ x }} "),
+                Token::Variable(Variable::single("y"))
+            ])
+        );
+    }
+
+    #[test]
+    fn escaping_can_be_disabled() {
+        let tokens: Result<Vec<_>> = Tokenize::new(r"a\{{ x }}\\")
+            .with_escape_char(None)
+            .collect();
+        assert_eq!(
+            tokens,
+            Ok(vec![
+                Token::Str("a\\"),
+                Token::Variable(Variable::single("x")),
+                Token::Str("\\\\")
+            ])
+        );
+    }
+
+    #[test]
+    fn escape_char_can_be_changed() {
+        let tokens: Result<Vec<_>> = Tokenize::new(r"\`{{ x }}``\{{ y }}")
+            .with_escape_char(Some('`'))
+            .collect();
+        assert_eq!(
+            tokens,
+            Ok(vec![
+                Token::Str("\\"),
+                Token::Str("{{ x }}"),
+                Token::Str("`\\"),
+                Token::Variable(Variable::single("y"))
+            ])
+        );
+    }
+
+    #[test]
+    fn escaped_backslash_becomes_single_backslash() {
+        let tokens = tokenize(r"a\\{{ x }}");
+        assert_eq!(
+            tokens,
+            Ok(vec![
+                Token::Str("a"),
+                Token::Str("\\"),
+                Token::Variable(Variable::single("x"))
+            ])
+        );
+    }
+
+    #[test]
+    fn backslash_before_single_brace_is_kept() {
+        assert_eq!(tokenize(r"\{x}"), Ok(vec![Token::Str(r"\{x}")]));
+    }
+
+    #[test]
+    fn backslash_at_end_of_input_is_kept() {
+        assert_eq!(tokenize(r"text\"), Ok(vec![Token::Str(r"text\")]));
+        assert_eq!(
+            tokenize(r"{{ x }}\\"),
+            Ok(vec![
+                Token::Variable(Variable::single("x")),
+                Token::Str(r"\")
+            ])
+        );
+    }
+
+    #[test]
+    fn triple_braces_are_raw_variables() {
+        assert_eq!(
+            tokenize("a{{{ b.c }}}d{{{e}}}"),
+            Ok(vec![
+                Token::Str("a"),
+                Token::RawVariable(Variable::from_parts(["b", "c"])),
+                Token::Str("d"),
+                Token::RawVariable(Variable::single("e")),
+            ])
+        );
+    }
+
+    #[test]
+    fn unbalanced_triple_braces_fall_back_to_variable() {
+        assert_eq!(
+            tokenize("{{{ a }}"),
+            Ok(vec![
+                Token::Str("{"),
+                Token::Variable(Variable::single("a"))
+            ])
+        );
+    }
+
+    #[test]
+    fn error_byte_offset_points_at_offending_character() {
+        let input = "héllo\n{{#if a}}\n  {{ x.%y }}{{/if}}";
+        let err = tokenize(input).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::EmptyVariableSegment);
+        assert_eq!(err.location(), Location::new(6, 2));
+        assert_eq!(&input[err.byte_offset()..], ".%y }}{{/if}}");
+    }
+
+    #[test]
+    fn trailing_braces_are_literal_text() {
+        assert_eq!(tokenize("abc{"), Ok(vec![Token::Str("abc{")]));
+        assert_eq!(
+            tokenize("{{x}}{"),
+            Ok(vec![
+                Token::Variable(Variable::single("x")),
+                Token::Str("{")
+            ])
+        );
+        assert_eq!(tokenize("abc{{"), Ok(vec![Token::Str("abc{{")]));
+        assert_eq!(tokenize("{{"), Ok(vec![Token::Str("{{")]));
+        assert_eq!(tokenize("{{x}"), Ok(vec![Token::Str("{{x}")]));
+        assert_eq!(tokenize("abc}"), Ok(vec![Token::Str("abc}")]));
+        assert_eq!(tokenize("{"), Ok(vec![Token::Str("{")]));
+    }
+
+    #[test]
+    fn errors_inside_multiline_tags_report_true_location() {
+        assert_eq!(
+            tokenize("{{ a.\nb }}"),
+            Err(Error::new((5, 0), 5, ErrorKind::NewlineInVariableSegment))
+        );
+        assert_eq!(
+            tokenize("ab\n  {{ a.\nb }}"),
+            Err(Error::new((7, 1), 10, ErrorKind::NewlineInVariableSegment))
+        );
+        assert_eq!(
+            tokenize("{{#if a}}\n{{#each x.\n}}"),
+            Err(Error::new((10, 1), 20, ErrorKind::NewlineInVariableSegment))
+        );
+    }
+
+    #[test]
+    fn variable_locations_are_recorded_in_source_order() {
+        let mut tokens = Tokenize::new("{{a}}\n{{#if b}} {{{c}}}{{else}}{{d}}{{/if}}{{! e }}")
+            .with_variable_locations();
+        let all: Vec<_> = tokens.by_ref().map(Result::unwrap).collect();
+        assert_eq!(
+            all.iter().map(variable_count).collect::<Vec<_>>(),
+            [1, 0, 3, 0]
+        );
+        assert_eq!(
+            tokens.variable_locations(),
+            [
+                Location::new(0, 0),
+                Location::new(0, 1),
+                Location::new(10, 1),
+                Location::new(25, 1),
+            ]
+        );
+        tokens.forget_variable_locations(3);
+        assert_eq!(tokens.variable_locations(), [Location::new(25, 1)]);
+        let mut tokens = Tokenize::new("{{a}} {{#if b}}{{/if}}");
+        assert_eq!(tokens.by_ref().count(), 3);
+        assert_eq!(tokens.variable_locations(), []);
+    }
+
+    #[test]
+    fn custom_delimiters_replace_braces_everywhere() {
+        let delimiters = Delimiters::new("[[%", "%]]");
+        assert_eq!(
+            tokenize_with(
+                "{{x}}[[%#if a.b%]][[%{ raw }%]][[% else %]][[%> part %]][[%/if%]]\\[[%[[%!--c%]]--%]]",
+                delimiters
+            ),
+            Ok(vec![
+                Token::Str("{{x}}"),
+                Token::Block {
+                    condition: Variable::from_parts(["a", "b"]),
+                    comparison: None,
+                    then: vec![Token::RawVariable(Variable::single("raw"))],
+                    otherwise: vec![Token::Partial("part")],
+                },
+                Token::Str("[[%"),
+                Token::Comment("c%]]"),
+            ])
+        );
+    }
+
+    #[test]
+    fn custom_delimiters_are_literal_when_incomplete() {
+        let delimiters = Delimiters::new("<%", "%>");
+        assert_eq!(
+            tokenize_with("a<", delimiters.clone()),
+            Ok(vec![Token::Str("a<")])
+        );
+        assert_eq!(
+            tokenize_with("a<% b }}", delimiters.clone()),
+            Ok(vec![Token::Str("a<% b }}")])
+        );
+        assert_eq!(
+            tokenize_with("<%{ b %>", delimiters),
+            Ok(vec![Token::Str("<%{ b %>"),])
+        );
+    }
+
+    #[test]
+    fn errors_with_custom_delimiters_are_located() {
+        assert_eq!(
+            tokenize_with("ab\n<<<< c. >>>>", Delimiters::new("<<<<", ">>>>")),
+            Err(Error::new((6, 1), 9, ErrorKind::EmptyVariableSegment))
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_delimiters_panic() {
+        Delimiters::new("", "}}");
+    }
+
+    #[test]
+    fn errors_in_triple_braces_are_located() {
+        assert_eq!(
+            tokenize("{{{ a. }}}"),
+            Err(Error::new((5, 0), 5, ErrorKind::EmptyVariableSegment))
+        );
+    }
+
+    #[test]
+    fn json_tags_are_tokenized() {
+        assert_eq!(
+            tokenize("{{json a.b}}{{ json  c }}{{json}}{{json.x}}{{json }}"),
+            Ok(vec![
+                Token::JsonVariable(Variable::from_parts(["a", "b"])),
+                Token::JsonVariable(Variable::single("c")),
+                Token::Variable(Variable::single("json")),
+                Token::Variable(Variable::from_parts(["json", "x"])),
+                Token::Variable(Variable::single("json")),
+            ])
+        );
+        assert_eq!(
+            tokenize("{{json a. }}"),
+            Err(Error::new((8, 0), 8, ErrorKind::EmptyVariableSegment))
+        );
+    }
+
+    #[test]
+    fn lone_at_sign_is_not_a_variable() {
+        assert_eq!(
+            tokenize("email me {{@}} or {{ @ }}"),
+            Ok(vec![Token::Str("email me {{@}} or {{ @ }}")])
+        );
+    }
+
+    #[test]
+    fn unknown_special_variables_are_reserved() {
+        assert_eq!(
+            tokenize("{{#each a}}\n {{ @indx }}{{/each}}"),
+            Err(Error::new((4, 1), 16, ErrorKind::ReservedName))
+        );
+        assert_eq!(
+            tokenize("{{#if @nope}}{{/if}}"),
+            Err(Error::new((6, 0), 6, ErrorKind::ReservedName))
+        );
+        assert_eq!(
+            tokenize("{{@index}}{{{@key}}}"),
+            Ok(vec![
+                Token::Variable(Variable::single("@index")),
+                Token::RawVariable(Variable::single("@key")),
+            ])
+        );
+    }
+
+    #[test]
+    fn partials_are_tokenized_by_name() {
+        assert_eq!(
+            tokenize("a{{> item }}{{>dir/file.hbs}}"),
+            Ok(vec![
+                Token::Str("a"),
+                Token::Partial("item"),
+                Token::Partial("dir/file.hbs"),
+            ])
+        );
+    }
+
+    #[test]
+    fn if_blocks_are_tokenized_with_both_branches() {
+        let tokens = tokenize("a{{#if x.y}}b{{z}}{{ else }}c{{/if}}d");
+        assert_eq!(
+            tokens,
+            Ok(vec![
+                Token::Str("a"),
+                Token::Block {
+                    condition: Variable::from_parts(["x", "y"]),
+                    comparison: None,
+                    then: vec![Token::Str("b"), Token::Variable(Variable::single("z"))],
+                    otherwise: vec![Token::Str("c")],
+                },
+                Token::Str("d"),
+            ])
+        );
+    }
+
+    #[test]
+    fn nested_if_blocks_are_tokenized() {
+        let tokens = tokenize("{{#if a}}{{#if b}}x{{/if}}{{else}}y{{/if}}");
+        assert_eq!(
+            tokens,
+            Ok(vec![Token::Block {
+                condition: Variable::single("a"),
+                comparison: None,
+                then: vec![Token::Block {
+                    condition: Variable::single("b"),
+                    comparison: None,
+                    then: vec![Token::Str("x")],
+                    otherwise: vec![],
+                }],
+                otherwise: vec![Token::Str("y")],
+            }])
+        );
+    }
+
+    #[test]
+    fn if_comparisons_are_tokenized() {
+        let block = |op, rhs| {
+            Ok(vec![Token::Block {
+                condition: Variable::single("count"),
+                comparison: Some(Comparison { op, rhs }),
+                then: vec![Token::Str("x")],
+                otherwise: vec![],
+            }])
+        };
+        assert_eq!(
+            tokenize("{{#if count > 10}}x{{/if}}"),
+            block(CompareOp::Gt, Operand::Number("10"))
+        );
+        assert_eq!(
+            tokenize("{{#if count<=-1.5 }}x{{/if}}"),
+            block(CompareOp::Le, Operand::Number("-1.5"))
+        );
+        assert_eq!(
+            tokenize("{{#if count != a.b}}x{{/if}}"),
+            block(
+                CompareOp::Ne,
+                Operand::Variable(Variable::from_parts(["a", "b"]))
+            )
+        );
+        assert_eq!(
+            tokenize("{{#if count == }}x{{/if}}"),
+            Err(Error::new((15, 0), 15, ErrorKind::EmptyVariableSegment))
+        );
+        assert_eq!(
+            tokenize("{{#if a > 1 && nonsense}}yes{{/if}}"),
+            Err(Error::new((12, 0), 12, ErrorKind::TooManyVariablesInBlock))
+        );
+        assert_eq!(
+            tokenize("{{#if a > 1  }}yes{{/if}}").map(|t| t.len()),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn each_blocks_are_tokenized() {
+        let tokens = tokenize("{{#each items}}{{@index}}: {{this.name}}{{/each}}");
+        assert_eq!(
+            tokens,
+            Ok(vec![Token::Each {
+                var: Variable::single("items"),
+                body: vec![
+                    Token::Variable(Variable::single("@index")),
+                    Token::Str(": "),
+                    Token::Variable(Variable::from_parts(["this", "name"])),
+                ],
+            }])
+        );
+    }
+
+    #[test]
+    fn mismatched_block_close_is_an_error() {
+        assert_eq!(
+            tokenize("{{#each a}}{{#if b}}{{/each}}{{/if}}"),
+            Err(Error::new((20, 0), 20, ErrorKind::UnmatchedBlockTag))
+        );
+        assert_eq!(
+            tokenize("{{#each a}}{{else}}{{/each}}"),
+            Err(Error::new((11, 0), 11, ErrorKind::UnmatchedBlockTag))
+        );
+    }
+
+    #[test]
+    fn unclosed_if_block_reports_location_of_opening_tag() {
+        assert_eq!(
+            tokenize("line\n  {{#if a}}{{#if b}}{{/if}}"),
+            Err(Error::new((2, 1), 7, ErrorKind::UnclosedBlock))
+        );
+    }
+
+    #[test]
+    fn deeply_nested_blocks_are_an_error() {
+        let nested = |depth| "{{#if a}}".repeat(depth) + &"{{/if}}".repeat(depth);
+        assert!(tokenize(&nested(MAX_BLOCK_DEPTH)).is_ok());
+        assert_eq!(
+            tokenize(&nested(MAX_BLOCK_DEPTH + 1)),
+            Err(Error::new((576, 0), 576, ErrorKind::BlocksNestedTooDeeply))
+        );
+        assert_eq!(
+            tokenize(&"{{#if a}}".repeat(100_000)),
+            Err(Error::new((576, 0), 576, ErrorKind::BlocksNestedTooDeeply))
+        );
+    }
+
+    #[test]
+    fn stray_block_tags_are_errors() {
+        assert_eq!(
+            tokenize("x {{/if}}"),
+            Err(Error::new((2, 0), 2, ErrorKind::UnmatchedBlockTag))
+        );
+        assert_eq!(
+            tokenize("{{else}}"),
+            Err(Error::new((0, 0), 0, ErrorKind::UnmatchedBlockTag))
+        );
+        assert_eq!(
+            tokenize("{{#if a}}{{else}}{{else}}{{/if}}"),
+            Err(Error::new((17, 0), 17, ErrorKind::UnmatchedBlockTag))
+        );
+    }
+
+    #[test]
+    fn if_without_condition_is_an_error() {
+        assert_eq!(
+            tokenize("{{#if }}{{/if}}"),
+            Err(Error::new((6, 0), 6, ErrorKind::EmptyVariableSegment))
+        );
+    }
+
     #[test]
     fn parse_segment_stops_on_non_alphanumeric_chars() {
         let r = try_parse_variable_segment("x}".as_bytes()).map(str_from_utf8);
@@ -473,7 +1666,7 @@ export THING=$SOME_VAR"
     fn parse_template_inner_parses_the_start_of_a_template() {
         let s = "some.txt }}h1";
         let cs = s.as_bytes();
-        let (var, offset) = parse_template_inner(cs).unwrap().unwrap();
+        let (var, offset) = parse_template_inner(cs, b"}}", false).unwrap().unwrap();
         assert_eq!(offset, s.len() - 2, "stops at template end");
         assert_eq!(
             &var,
@@ -494,7 +1687,7 @@ export THING=$SOME_VAR"
         fn parse_template_inner_allows_any_amount_of_whitespace(whitespace in "[ ]*") {
             let s = "test".to_owned() + &whitespace + "}}";
             let cs = s.as_bytes();
-            let (var, _) = parse_template_inner(cs).unwrap().unwrap();
+            let (var, _) = parse_template_inner(cs, b"}}", false).unwrap().unwrap();
             prop_assert_eq!(
                 &var,
                 &Variable::single("test")
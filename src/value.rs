@@ -1,5 +1,7 @@
 use std::{borrow::Cow, collections::BTreeMap};
 
+use crate::{Segment, Variable};
+
 /// Object value with 0 or more properties
 #[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct Object<'a> {
@@ -55,6 +57,8 @@ pub enum Value<'a> {
     String(Cow<'a, str>),
     /// Object with additional level of path
     Object(Object<'a>),
+    /// List of values, can be looped over with `{{#each}}`
+    Array(Vec<Value<'a>>),
 }
 impl<'a> From<Object<'a>> for Value<'a> {
     fn from(value: Object<'a>) -> Self {
@@ -81,6 +85,14 @@ where T: Into<Value<'a>> {
         }
     }
 }
+impl<'a, T> From<Vec<T>> for Value<'a>
+where
+    T: Into<Value<'a>>,
+{
+    fn from(value: Vec<T>) -> Self {
+        Self::Array(value.into_iter().map(Into::into).collect())
+    }
+}
 macro_rules! value_from_num {
     ($typ:ident) => {
         impl<'a> From<$typ> for Value<'a> {
@@ -131,6 +143,16 @@ impl<'a> Value<'a> {
         }
     }
 
+    #[allow(missing_docs)]
+    #[must_use]
+    pub fn as_array(&self) -> Option<&[Value<'a>]> {
+        if let Self::Array(v) = self {
+            Some(v)
+        } else {
+            None
+        }
+    }
+
     #[allow(missing_docs)]
     #[must_use]
     pub fn as_string(&self) -> Option<&Cow<'a, str>> {
@@ -141,6 +163,115 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// Whether the value counts as true for `{{#if}}` blocks
+    ///
+    /// Only empty strings and arrays are falsy, a variable with no value is also treated as false. Note
+    /// that `bool`s convert to the strings `"true"` and `"false"`, so both are truthy
+    ///
+    /// ```
+    /// # use handybars::{Object, Value};
+    /// assert!(Value::from("a").is_truthy());
+    /// assert!(!Value::from("").is_truthy());
+    /// assert!(Value::from(Object::new()).is_truthy());
+    /// ```
+    #[must_use]
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::String(s) => !s.is_empty(),
+            Value::Object(_) => true,
+            Value::Array(a) => !a.is_empty(),
+        }
+    }
+
+    /// Compare two values structurally
+    ///
+    /// Objects are equal if they have the same set of property names with equal values, the
+    /// order properties were added in is never significant. Arrays are compared element by
+    /// element and strings by content.
+    ///
+    /// Properties are currently stored sorted by name so the derived `PartialEq` gives the
+    /// same answer, this exists so that code which relies on order not mattering can say so
+    /// rather than depend on how objects are stored. It can also compare values with
+    /// different lifetimes.
+    ///
+    /// ```
+    /// # use handybars::{Object, Value};
+    /// let ab = Value::from(Object::new().with_property("a", "1").with_property("b", "2"));
+    /// let ba = Value::from(Object::new().with_property("b", "2").with_property("a", "1"));
+    /// assert!(ab.deep_eq(&ba));
+    /// ```
+    #[must_use]
+    pub fn deep_eq(&self, other: &Value<'_>) -> bool {
+        match (self, other) {
+            (Value::String(l), Value::String(r)) => l == r,
+            (Value::Array(l), Value::Array(r)) => {
+                l.len() == r.len() && l.iter().zip(r).all(|(l, r)| l.deep_eq(r))
+            }
+            (Value::Object(l), Value::Object(r)) => {
+                l.values.len() == r.values.len()
+                    && l.values
+                        .iter()
+                        .all(|(k, l)| r.values.get(k.as_ref()).is_some_and(|r| l.deep_eq(r)))
+            }
+            _ => false,
+        }
+    }
+
+    /// Every leaf of the value along with its full path
+    ///
+    /// Leaves are strings and empty objects or arrays, array elements are given by index.
+    /// Entries are in path order with object properties sorted by name. A value which is not
+    /// an object or array has no path, and so gives nothing. Properties whose names can't be
+    /// used in a variable, such as empty names or ones with spaces, are skipped
+    ///
+    /// ```
+    /// # use handybars::{Object, Value, Variable};
+    /// let value = Value::from(Object::new().with_property("user", Object::new().with_property("name", "Alice")));
+    /// assert_eq!(
+    ///     value.flatten(),
+    ///     [("user.name".parse::<Variable>().unwrap(), &Value::from("Alice"))]
+    /// );
+    /// ```
+    #[must_use]
+    pub fn flatten(&self) -> Vec<(Variable<'static>, &Value<'a>)> {
+        let mut leaves = Vec::new();
+        self.flatten_into(&mut Vec::new(), &mut leaves);
+        leaves
+    }
+    fn flatten_into<'v>(
+        &'v self,
+        path: &mut Vec<Segment<'static>>,
+        leaves: &mut Vec<(Variable<'static>, &'v Value<'a>)>,
+    ) {
+        let mut visit = |segment, child: &'v Value<'a>| {
+            path.push(segment);
+            child.flatten_into(path, leaves);
+            path.pop();
+        };
+        match self {
+            Value::Object(obj) if !obj.values.is_empty() => {
+                for (key, child) in &obj.values {
+                    if let Some(segment) = Segment::property(key) {
+                        visit(segment, child);
+                    }
+                }
+            }
+            Value::Array(items) if !items.is_empty() => {
+                for (i, child) in items.iter().enumerate() {
+                    visit(Segment::Index(i), child);
+                }
+            }
+            _ => {
+                let var = match path.as_slice() {
+                    [] => return,
+                    [first] => Variable::single_unchecked(first.clone()),
+                    all => Variable::from_segments(all.to_vec()),
+                };
+                leaves.push((var, self));
+            }
+        }
+    }
+
     /// Returns `true` if the value is [`Object`].
     ///
     /// [`Object`]: Value::Object
@@ -149,6 +280,25 @@ impl<'a> Value<'a> {
         matches!(self, Self::Object(..))
     }
 
+    /// Returns `true` if the value is [`Array`].
+    ///
+    /// [`Array`]: Value::Array
+    #[must_use]
+    pub fn is_array(&self) -> bool {
+        matches!(self, Self::Array(..))
+    }
+
+    /// Child value for a segment of a variable path
+    ///
+    /// Arrays can only be indexed and return `None` for indexes past their end
+    pub(crate) fn lookup(&self, segment: &Segment<'_>) -> Option<&Value<'a>> {
+        match self {
+            Value::Object(obj) => obj.property(&segment.to_key()),
+            Value::Array(items) => items.get(segment.as_index()?),
+            Value::String(_) => None,
+        }
+    }
+
     /// Returns `true` if the value is [`String`].
     ///
     /// [`String`]: Value::String
@@ -165,8 +315,8 @@ impl From<String> for Value<'static> {
 
 #[cfg(test)]
 mod tests {
+    use crate::{Object, Value, Variable};
     use std::borrow::Cow;
-    use crate::Value;
 
     #[test]
     fn value_from_hex() {
@@ -217,6 +367,94 @@ mod tests {
         assert_eq!(Value::String(Cow::from("42.242")), From::from(42.242f64));
     }
 
+    #[test]
+    fn deep_eq_ignores_property_order() {
+        let nested = |first: &'static str, second: &'static str| {
+            let mut obj = Object::new();
+            obj.add_property(first, first).add_property(second, second);
+            Value::from(vec![Value::from(Object::new().with_property("inner", obj))])
+        };
+        assert!(nested("a", "b").deep_eq(&nested("b", "a")));
+        assert_eq!(nested("a", "b"), nested("b", "a"));
+    }
+
+    #[test]
+    fn deep_eq_compares_arrays_in_order_and_by_kind() {
+        let ab = Value::from(vec!["a", "b"]);
+        assert!(ab.deep_eq(&Value::from(vec!["a", "b"])));
+        assert!(!ab.deep_eq(&Value::from(vec!["b", "a"])));
+        assert!(!ab.deep_eq(&Value::from(vec!["a"])));
+        assert!(!Value::from("").deep_eq(&Value::from(Vec::<Value>::new())));
+        assert!(!Value::from(Object::new().with_property("a", "1"))
+            .deep_eq(&Value::from(Object::new().with_property("a", "2"))));
+        assert!(!Value::from(Object::new().with_property("a", "1"))
+            .deep_eq(&Value::from(Object::new().with_property("b", "1"))));
+    }
+
+    #[test]
+    fn value_from_vec() {
+        assert_eq!(
+            Value::Array(vec![
+                Value::String(Cow::from("a")),
+                Value::String(Cow::from("1"))
+            ]),
+            From::from(vec![Value::from("a"), Value::from(1)])
+        );
+        assert_eq!(
+            Value::Array(vec![Value::String(Cow::from("1"))]),
+            From::from(vec![1])
+        );
+    }
+
+    #[test]
+    fn flatten_gives_leaves_with_their_paths() {
+        let value = Value::from(
+            Object::new().with_property("title", "t").with_property(
+                "user",
+                Object::new()
+                    .with_property("name", "Alice")
+                    .with_property("roles", vec!["admin", "dev"])
+                    .with_property("tags", Vec::<Value>::new()),
+            ),
+        );
+        let flat: Vec<_> = value
+            .flatten()
+            .into_iter()
+            .map(|(var, value)| (var.to_string(), value.clone()))
+            .collect();
+        assert_eq!(
+            flat,
+            vec![
+                ("title".to_owned(), Value::from("t")),
+                ("user.name".to_owned(), Value::from("Alice")),
+                ("user.roles.0".to_owned(), Value::from("admin")),
+                ("user.roles.1".to_owned(), Value::from("dev")),
+                ("user.tags".to_owned(), Value::Array(vec![])),
+            ]
+        );
+        assert_eq!(
+            value.flatten()[2].0,
+            "user.roles.0".parse::<Variable>().unwrap()
+        );
+        assert!(Value::from("x").flatten().is_empty());
+    }
+
+    #[test]
+    fn flatten_skips_properties_which_cannot_be_variables() {
+        let value = Value::from(
+            Object::new()
+                .with_property("", "empty")
+                .with_property("sp ace", "space")
+                .with_property("ok", Object::new().with_property("a\nb", "newline"))
+                .with_property("fine", "yes"),
+        );
+        let flat = value.flatten();
+        assert_eq!(flat, [(Variable::single("fine"), &Value::from("yes"))]);
+        for (var, _) in flat {
+            assert_eq!(var.to_string().parse::<Variable>(), Ok(var));
+        }
+    }
+
     #[test]
     fn value_from_char() {
         assert_eq!(Value::String(Cow::from("*")), From::from('*'));
@@ -0,0 +1,133 @@
+#![cfg(feature = "cli")]
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+fn run(args: &[&str], env: &[(&str, &str)], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_handybars"))
+        .args(args)
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run handybars");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn renders_stdin_with_defines() {
+    let out = run(&["-", "-D", "a.b=x", "--define=c=y"], &[], "{{ a.b }} {{c}}");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "x y");
+}
+
+#[test]
+fn renders_file_argument() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cli_template.hbs");
+    std::fs::write(&path, "{{#if a}}{{a}}{{/if}}").unwrap();
+    let out = run(&[path.to_str().unwrap(), "-D=a=file"], &[], "");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "file");
+}
+
+#[test]
+fn env_variables_are_defined_under_env() {
+    let env = [("HANDYBARS_CLI_TEST", "from env"), ("HANDYBARS_CLI_OTHER", "x")];
+    let out = run(
+        &["--env", "-D", "env.HANDYBARS_CLI_OTHER=define"],
+        &env,
+        "{{ env.HANDYBARS_CLI_TEST }}, {{ env.HANDYBARS_CLI_OTHER }}",
+    );
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "from env, define");
+}
+
+#[test]
+fn env_is_not_used_without_flag() {
+    let out = run(&[], &[("HANDYBARS_CLI_TEST", "x")], "{{ env.HANDYBARS_CLI_TEST }}");
+    assert_eq!(out.status.code(), Some(1));
+}
+
+#[test]
+fn errors_exit_non_zero_with_position() {
+    let out = run(&[], &[], "ok\n  {{ a. }}");
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        "error: parse: empty variable segment name at line 2 column 7\n"
+    );
+}
+
+#[test]
+fn data_file_properties_are_defined() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cli_data.json");
+    std::fs::write(
+        &path,
+        r#"{"user": {"name": "Alice \"A\"", "roles": ["admin", "dev"], "age": 42}, "on": true, "b": "data"}"#,
+    )
+    .unwrap();
+    let data = format!("--data={}", path.to_str().unwrap());
+    let out = run(
+        &[&data, "-D", "b=define"],
+        &[],
+        "{{user.name}} {{user.age}} {{#each user.roles}}{{this}},{{/each}} {{on}} {{b}}",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "Alice \"A\" 42 admin,dev, true define"
+    );
+}
+
+#[test]
+fn numeric_data_keys_stay_object_properties() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cli_numeric_data.json");
+    std::fs::write(
+        &path,
+        r#"{"scores": {"2023": "good", "18446744073709551615": "big"}}"#,
+    )
+    .unwrap();
+    let out = run(
+        &["--data", path.to_str().unwrap(), "-D", "a.99999999999999=x"],
+        &[],
+        "{{#each scores}}{{@key}}={{this}},{{/each}} {{a.99999999999999}}",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "18446744073709551615=big,2023=good, x"
+    );
+}
+
+#[test]
+fn invalid_data_file_is_an_error() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cli_bad_data.json");
+    std::fs::write(&path, r#"{"a": [1, }"#).unwrap();
+    let out = run(&["--data", path.to_str().unwrap()], &[], "");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        format!("error: {}: expected a value at byte 10\n", path.display())
+    );
+}
+
+#[test]
+fn usage_errors_exit_non_zero_without_panicking() {
+    for args in [&["missing_template.hbs"][..], &["-D", "novalue"], &["--define"]] {
+        let out = run(args, &[], "");
+        assert_eq!(out.status.code(), Some(1), "{args:?}");
+        assert!(
+            String::from_utf8(out.stderr).unwrap().starts_with("error: "),
+            "{args:?}"
+        );
+    }
+}